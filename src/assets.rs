@@ -0,0 +1,308 @@
+//! Access to the game's music files. They can be extracted to a directory, inside the `.obb`
+//! archive of the Android version or held in memory.
+
+use crate::{MsmError, Result};
+use lewton::inside_ogg::OggStreamReader;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Folder of the music files inside the Android `.obb` archive
+const ARCHIVE_MUSIC_FOLDER: &str = "assets/audio/music/";
+
+/// File formats of sound segments
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleFormat {
+    Wav,
+    Ogg,
+}
+
+/// The undecoded sound segment of a monster
+pub struct Sample {
+    /// File name relative to the music folder
+    pub name: String,
+    /// Location of the file, for messages
+    pub path: String,
+    pub format: SampleFormat,
+    pub bytes: Vec<u8>,
+}
+
+impl Sample {
+    /// Reads the sample rate and channel count from the file header, without decoding the audio
+    pub fn spec(&self) -> Result<(u32, u16)> {
+        match self.format {
+            SampleFormat::Wav => match hound::WavReader::new(Cursor::new(&self.bytes)) {
+                Ok(reader) => Ok((reader.spec().sample_rate, reader.spec().channels)),
+                Err(e) => Err(MsmError::SegmentOpen {
+                    path: self.path.clone(),
+                    source: e.into(),
+                }),
+            },
+            SampleFormat::Ogg => match OggStreamReader::new(Cursor::new(&self.bytes)) {
+                Ok(reader) => Ok((
+                    reader.ident_hdr.audio_sample_rate,
+                    reader.ident_hdr.audio_channels as u16,
+                )),
+                Err(source) => Err(MsmError::SegmentRead {
+                    path: self.path.clone(),
+                    source,
+                }),
+            },
+        }
+    }
+}
+
+/// A place the music files are read from. Implementors only need to provide access to the files
+/// by name, the file layout of the game is handled by the provided methods
+pub trait AssetSource: Send + Sync {
+    /// Reads the file with the given name, relative to the music folder. Returns `None` if the
+    /// file doesn't exist
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// A description of where a file is located, for messages
+    fn display_path(&self, name: &str) -> String;
+
+    /// Names of all files in the music folder
+    fn file_names(&self) -> io::Result<Vec<String>>;
+
+    /// Reads the MIDI file of an island, e.g. `world01.mid` for world `01`
+    fn open_midi(&self, world: &str) -> Result<Vec<u8>> {
+        let name = format!("world{}.mid", world);
+        let path = self.display_path(&name);
+        match self.read(&name) {
+            Ok(Some(bytes)) => Ok(bytes),
+            Ok(None) => Err(MsmError::MidiOpen {
+                path,
+                source: io::ErrorKind::NotFound.into(),
+            }),
+            Err(source) => Err(MsmError::MidiOpen { path, source }),
+        }
+    }
+
+    /// Whether a file with the given name exists. Sources should override this when they can check
+    /// without reading the file
+    fn exists(&self, name: &str) -> bool {
+        matches!(self.read(name), Ok(Some(_)))
+    }
+
+    /// The file name and format of the segment played by a track for one of its sounds. Uses the
+    /// `.wav` file if there is one and the `.ogg` file otherwise
+    fn resolve_sample(
+        &self,
+        island: &str,
+        track: &str,
+        sound: &str,
+    ) -> Option<(String, SampleFormat)> {
+        let base_name = sample_name(island, track, sound);
+        [(SampleFormat::Wav, "wav"), (SampleFormat::Ogg, "ogg")]
+            .into_iter()
+            .map(|(format, extension)| (format!("{}.{}", base_name, extension), format))
+            .find(|(name, _)| self.exists(name))
+    }
+
+    /// Reads the segment played by a track for one of its sounds, see
+    /// [`resolve_sample`](AssetSource::resolve_sample)
+    fn open_sample(&self, island: &str, track: &str, sound: &str) -> Result<Sample> {
+        let (name, format) = match self.resolve_sample(island, track, sound) {
+            Some(it) => it,
+            None => {
+                return Err(MsmError::SegmentOpen {
+                    path: self.display_path(&format!("{}.ogg", sample_name(island, track, sound))),
+                    source: io::Error::from(io::ErrorKind::NotFound).into(),
+                })
+            }
+        };
+        let path = self.display_path(&name);
+        match self.read(&name) {
+            Ok(Some(bytes)) => Ok(Sample {
+                name,
+                path,
+                format,
+                bytes,
+            }),
+            Ok(None) => Err(MsmError::SegmentOpen {
+                path,
+                source: io::Error::from(io::ErrorKind::NotFound).into(),
+            }),
+            Err(source) => Err(MsmError::SegmentOpen {
+                path,
+                source: source.into(),
+            }),
+        }
+    }
+}
+
+/// The file name of a segment without extension. All dipsters share the files of Plant Island
+pub fn sample_name(island: &str, track: &str, sound: &str) -> String {
+    if track == "Q_Monster" {
+        format!("01-Q_Monster_{}", sound)
+    } else {
+        format!("{}-{}_{}", island, track, sound)
+    }
+}
+
+/// Opens the music files at `path`. Files are treated as `.obb` or `.zip` archives, anything else
+/// as a directory
+pub fn open(path: &str) -> Result<Box<dyn AssetSource>> {
+    if Path::new(path).is_file() {
+        Ok(Box::new(ArchiveSource::open(path)?))
+    } else {
+        Ok(Box::new(DirectorySource::new(path)))
+    }
+}
+
+/// Music files in a directory, e.g. `data/audio/music/` of the Steam version
+pub struct DirectorySource {
+    path: String,
+}
+
+impl DirectorySource {
+    pub fn new(path: &str) -> Self {
+        DirectorySource {
+            path: path.to_owned(),
+        }
+    }
+}
+
+impl AssetSource for DirectorySource {
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(Path::new(&self.path).join(name)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn display_path(&self, name: &str) -> String {
+        format!("{}/{}", self.path, name)
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        Path::new(&self.path).join(name).is_file()
+    }
+
+    fn file_names(&self) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.extend(entry.file_name().into_string());
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+}
+
+/// Music files inside a `.obb` or `.zip` archive
+pub struct ArchiveSource {
+    path: String,
+    /// Folder of the music files inside the archive, including a trailing slash
+    folder: String,
+    archive: Mutex<ZipArchive<File>>,
+}
+
+impl ArchiveSource {
+    pub fn open(path: &str) -> Result<Self> {
+        let archive = File::open(path)
+            .map_err(ZipError::Io)
+            .and_then(ZipArchive::new)
+            .map_err(|source| MsmError::ArchiveOpen {
+                path: path.to_owned(),
+                source,
+            })?;
+        // The music folder is wherever the island MIDI files are, in case the archive was
+        // repacked from the extracted files
+        let folder = archive
+            .file_names()
+            .filter(|name| {
+                let file_name = name.rsplit('/').next().unwrap_or(name);
+                file_name.starts_with("world") && file_name.ends_with(".mid")
+            })
+            .map(|name| name[..name.rfind('/').map_or(0, |it| it + 1)].to_owned())
+            .min_by_key(|it| (it != ARCHIVE_MUSIC_FOLDER, it.len()))
+            .unwrap_or_else(|| ARCHIVE_MUSIC_FOLDER.to_owned());
+        Ok(ArchiveSource {
+            path: path.to_owned(),
+            folder,
+            archive: Mutex::new(archive),
+        })
+    }
+}
+
+impl AssetSource for ArchiveSource {
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut archive = self.archive.lock().unwrap();
+        let mut file = match archive.by_name(&format!("{}{}", self.folder, name)) {
+            Ok(file) => file,
+            Err(ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut bytes = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    fn display_path(&self, name: &str) -> String {
+        format!("{}/{}{}", self.path, self.folder, name)
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        let mut archive = self.archive.lock().unwrap();
+        let exists = archive.by_name(&format!("{}{}", self.folder, name)).is_ok();
+        exists
+    }
+
+    fn file_names(&self) -> io::Result<Vec<String>> {
+        let archive = self.archive.lock().unwrap();
+        let mut names: Vec<String> = archive
+            .file_names()
+            .filter_map(|name| name.strip_prefix(&self.folder))
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .map(|name| name.to_owned())
+            .collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+}
+
+/// Music files held in memory, e.g. synthetic files for tests
+#[derive(Default)]
+pub struct MemorySource {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file, replacing any file with the same name
+    pub fn insert(&mut self, name: &str, bytes: Vec<u8>) {
+        self.files.insert(name.to_owned(), bytes);
+    }
+}
+
+impl AssetSource for MemorySource {
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.files.get(name).cloned())
+    }
+
+    fn display_path(&self, name: &str) -> String {
+        format!("<memory>/{}", name)
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.files.contains_key(name)
+    }
+
+    fn file_names(&self) -> io::Result<Vec<String>> {
+        let mut names: Vec<String> = self.files.keys().cloned().collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+}
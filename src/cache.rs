@@ -0,0 +1,270 @@
+//! Cache of decoded and resampled sound segments. Monsters play the same segment many times and
+//! the dipster segments are shared by all islands, so each segment only has to be decoded once.
+
+use crate::assets::Sample;
+use crate::resample::Quality;
+use crate::{MsmError, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Decoded segments kept in memory before the least recently used ones are dropped, in bytes
+const MEMORY_LIMIT: usize = 1 << 30;
+/// Identifies the files of the on-disk cache, followed by a format version
+const MAGIC: &[u8; 8] = b"MSMPCM\0\x01";
+
+/// A decoded segment, as one sample vector per channel
+pub type Segment = Arc<Vec<Vec<f32>>>;
+
+struct Entry {
+    segment: Segment,
+    /// Value of `Memory::clock` when the entry was last used
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Memory {
+    entries: HashMap<String, Entry>,
+    /// Size of all entries in bytes
+    size: usize,
+    clock: u64,
+}
+
+/// Decoded segments keyed by their resolved path, the sample rate and the resampling quality.
+/// Can be shared by several threads. With a cache directory, segments are also saved to disk so
+/// later runs don't have to decode them again
+#[derive(Default)]
+pub struct SampleCache {
+    memory: Mutex<Memory>,
+    directory: Option<String>,
+}
+
+impl SampleCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache that also saves segments as files in `directory`. The files are named after a hash
+    /// of the original file and the output format, so changed game files are never mixed up
+    pub fn with_directory(directory: &str) -> Self {
+        SampleCache {
+            memory: Mutex::default(),
+            directory: Some(directory.to_owned()),
+        }
+    }
+
+    /// Returns the cached segment at `path`, otherwise opens it with `open` and decodes it with
+    /// `decode`
+    pub(crate) fn get_or_decode(
+        &self,
+        path: &str,
+        sample_rate: u32,
+        quality: Quality,
+        open: impl FnOnce() -> Result<Sample>,
+        decode: impl FnOnce(Sample) -> Result<Vec<Vec<f32>>>,
+    ) -> Result<Segment> {
+        let key = format!("{}@{}:{:?}", path, sample_rate, quality);
+        if let Some(segment) = self.memory.lock().unwrap().get(&key) {
+            return Ok(segment);
+        }
+
+        // The lock isn't held while decoding, so other threads can use the cache in the meantime.
+        // Rarely this decodes a segment twice
+        let segment = match &self.directory {
+            Some(directory) => {
+                let sample = open()?;
+                let file = Path::new(directory).join(format!(
+                    "{:016x}.pcm",
+                    fnv1a(&[
+                        &sample.bytes,
+                        &sample_rate.to_le_bytes(),
+                        format!("{:?}", quality).as_bytes(),
+                    ])
+                ));
+                match fs::read(&file).ok().and_then(|bytes| from_bytes(&bytes)) {
+                    Some(segment) => segment,
+                    None => {
+                        let segment = decode(sample)?;
+                        if let Err(source) = fs::create_dir_all(directory)
+                            .and_then(|_| fs::write(&file, to_bytes(&segment)))
+                        {
+                            return Err(MsmError::CacheWrite {
+                                path: file.to_string_lossy().into_owned(),
+                                source,
+                            });
+                        }
+                        segment
+                    }
+                }
+            }
+            None => decode(open()?)?,
+        };
+        let segment = Arc::new(segment);
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key, Arc::clone(&segment));
+        Ok(segment)
+    }
+}
+
+impl Memory {
+    fn get(&mut self, key: &str) -> Option<Segment> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        Some(Arc::clone(&entry.segment))
+    }
+
+    fn insert(&mut self, key: String, segment: Segment) {
+        self.clock += 1;
+        self.size += segment_size(&segment);
+        let old = self.entries.insert(
+            key,
+            Entry {
+                segment,
+                last_used: self.clock,
+            },
+        );
+        if let Some(old) = old {
+            self.size -= segment_size(&old.segment);
+        }
+        while self.size > MEMORY_LIMIT && self.entries.len() > 1 {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.size -= segment_size(&entry.segment);
+        }
+    }
+}
+
+fn segment_size(segment: &[Vec<f32>]) -> usize {
+    segment.iter().map(|it| it.len() * 4).sum()
+}
+
+/// 64-bit FNV-1a hash. Unlike `DefaultHasher` it is the same across Rust versions, which matters
+/// for file names on disk
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in parts.iter().flat_map(|it| it.iter()) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Serializes a segment as the magic bytes, the channel count and the frame count followed by
+/// the samples of each channel, all little endian
+fn to_bytes(segment: &[Vec<f32>]) -> Vec<u8> {
+    let frames = segment.first().map_or(0, |it| it.len());
+    let mut bytes = Vec::with_capacity(20 + segment_size(segment));
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&(frames as u64).to_le_bytes());
+    for channel in segment {
+        for sample in channel {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+/// Reads a segment written by `to_bytes`. Returns `None` for anything else, e.g. a truncated file
+fn from_bytes(bytes: &[u8]) -> Option<Vec<Vec<f32>>> {
+    if bytes.get(..8)? != MAGIC {
+        return None;
+    }
+    let channels = u32::from_le_bytes(bytes.get(8..12)?.try_into().ok()?) as usize;
+    let frames = u64::from_le_bytes(bytes.get(12..20)?.try_into().ok()?) as usize;
+    let data = bytes.get(20..)?;
+    // Audio files have at most `u16::MAX` channels. Without frames, a corrupt channel count
+    // would otherwise allocate that many empty channels
+    if channels == 0
+        || channels > u16::MAX as usize
+        || data.len() != channels.checked_mul(frames)?.checked_mul(4)?
+    {
+        return None;
+    }
+    if frames == 0 {
+        return Some(vec![vec![]; channels]);
+    }
+    Some(
+        data.chunks_exact(frames * 4)
+            .map(|channel| {
+                channel
+                    .chunks_exact(4)
+                    .map(|it| f32::from_le_bytes(it.try_into().unwrap()))
+                    .collect()
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::SampleFormat;
+
+    fn segment() -> Vec<Vec<f32>> {
+        vec![vec![0.0, 0.5, -1.0], vec![0.25, -0.25, 1.0]]
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(from_bytes(&to_bytes(&segment())), Some(segment()));
+        assert_eq!(from_bytes(&to_bytes(&[vec![]])), Some(vec![vec![]]));
+    }
+
+    #[test]
+    fn reject_corrupt_bytes() {
+        let bytes = to_bytes(&segment());
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[7] = 2;
+        assert_eq!(from_bytes(&wrong_magic), None);
+        for len in 0..bytes.len() {
+            assert_eq!(from_bytes(&bytes[..len]), None, "{} bytes", len);
+        }
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(from_bytes(&extra), None);
+        let mut channels = to_bytes(&[vec![]]);
+        channels[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(from_bytes(&channels), None);
+    }
+
+    #[test]
+    fn corrupt_file_is_decoded_again() {
+        let directory = std::env::temp_dir().join(format!("msm-cache-{}", std::process::id()));
+        let directory = directory.to_str().unwrap();
+        let open = || {
+            Ok(Sample {
+                name: "01-A_Monster_01.wav".to_owned(),
+                path: "01-A_Monster_01.wav".to_owned(),
+                format: SampleFormat::Wav,
+                bytes: vec![1, 2, 3],
+            })
+        };
+        SampleCache::with_directory(directory)
+            .get_or_decode("a", 44100, Quality::High, open, |_| Ok(segment()))
+            .unwrap();
+        let files: Vec<_> = fs::read_dir(directory)
+            .unwrap()
+            .map(|it| it.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+
+        fs::write(&files[0], &to_bytes(&segment())[..30]).unwrap();
+        let decoded = SampleCache::with_directory(directory)
+            .get_or_decode("a", 44100, Quality::High, open, |_| Ok(vec![vec![0.75]]))
+            .unwrap();
+        let rewritten = fs::read(&files[0]).unwrap();
+        fs::remove_dir_all(directory).unwrap();
+        assert_eq!(*decoded, [vec![0.75]]);
+        assert_eq!(from_bytes(&rewritten), Some(vec![vec![0.75]]));
+    }
+}
@@ -0,0 +1,351 @@
+//! Checks that the game files are complete: every sound played by the island MIDI files needs a
+//! readable sound segment.
+
+use crate::assets::{self, AssetSource};
+use crate::profile::Profile;
+use crate::{cprintln, parse, MsmError, Result};
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::io;
+
+/// What is wrong with a referenced sound segment
+#[derive(Debug, Clone)]
+pub enum Problem {
+    /// Neither a `.wav` nor an `.ogg` file exists
+    Missing,
+    /// The file exists but could not be read
+    Unreadable(String),
+    /// The sample rate or channel count differs from most other segments
+    Format { sample_rate: u32, channels: u16 },
+}
+
+/// A sound segment with a problem
+#[derive(Debug, Clone)]
+pub struct Issue {
+    /// File name of the segment. Without extension if the segment is missing
+    pub file: String,
+    /// Key of the monster in `monster_names.json`
+    pub monster: String,
+    pub problem: Problem,
+}
+
+/// Results for the MIDI file of one island
+#[derive(Debug)]
+pub struct IslandReport {
+    pub world: String,
+    /// The MIDI file could not be parsed
+    pub error: Option<MsmError>,
+    /// Number of distinct segments the island uses
+    pub samples: usize,
+    pub issues: Vec<Issue>,
+}
+
+#[derive(Debug)]
+pub struct CheckReport {
+    pub islands: Vec<IslandReport>,
+    /// The most common sample rate of all segments
+    pub sample_rate: u32,
+    /// The most common channel count of all segments
+    pub channels: u16,
+    /// Files in the music folder that no island uses
+    pub unreferenced: Vec<String>,
+    /// MIDI files of worlds that aren't a known island, e.g. `world11.mid`. They are not checked
+    pub ignored: Vec<String>,
+}
+
+impl CheckReport {
+    /// Number of missing or unreadable segments and unparsable MIDI files. Songs can't be created
+    /// while this is not 0
+    pub fn failures(&self) -> usize {
+        self.islands
+            .iter()
+            .map(|island| {
+                island.error.is_some() as usize
+                    + island
+                        .issues
+                        .iter()
+                        .filter(|it| !matches!(it.problem, Problem::Format { .. }))
+                        .count()
+            })
+            .sum()
+    }
+}
+
+/// Result of opening a segment, shared by all islands using it
+enum Outcome {
+    Found {
+        name: String,
+        sample_rate: u32,
+        channels: u16,
+    },
+    Missing,
+    Unreadable(String),
+}
+
+/// Parses every island MIDI file of `assets` and resolves each sound to its segment
+pub fn check(assets: &dyn AssetSource, profile: &Profile) -> Result<CheckReport> {
+    let file_names = assets.file_names().map_err(|source| MsmError::AssetList {
+        path: assets.display_path(""),
+        source,
+    })?;
+    let midi_regex = Regex::new(r"^world(\d+)\.mid$").unwrap();
+    let mut worlds: Vec<String> = file_names
+        .iter()
+        .filter_map(|name| Some(midi_regex.captures(name)?[1].to_owned()))
+        .collect();
+    worlds.sort_by_key(|it| (it.parse::<u32>().unwrap_or(u32::MAX), it.clone()));
+    let (worlds, ignored): (Vec<String>, Vec<String>) = worlds
+        .into_iter()
+        .partition(|world| crate::island_name(world).is_ok());
+    let ignored: Vec<String> = ignored
+        .into_iter()
+        .map(|world| format!("world{}.mid", world))
+        .collect();
+
+    let mut referenced: BTreeSet<String> = BTreeSet::new();
+    let mut outcomes: HashMap<String, Outcome> = HashMap::new();
+    // Segments of each island, as segment name without extension and monster
+    let mut islands: Vec<(String, Option<MsmError>, BTreeMap<String, String>)> = vec![];
+    for world in worlds {
+        referenced.insert(format!("world{}.mid", world));
+        let song = match parse::parse(assets, &world, &profile.island(&world), &[], &[]) {
+            Ok(song) => song,
+            Err(e) => {
+                islands.push((world, Some(e), BTreeMap::new()));
+                continue;
+            }
+        };
+        let mut segments = BTreeMap::new();
+        for track in song.tracks.iter() {
+            for sound in track.parts.iter().filter_map(|it| it.sound.as_ref()) {
+                let name = assets::sample_name(&world, &track.name, sound);
+                if !outcomes.contains_key(&name) {
+                    let outcome = match assets.open_sample(&world, &track.name, sound) {
+                        Ok(sample) => match sample.spec() {
+                            Ok((sample_rate, channels)) => Outcome::Found {
+                                name: sample.name,
+                                sample_rate,
+                                channels,
+                            },
+                            Err(e) => {
+                                referenced.insert(sample.name);
+                                Outcome::Unreadable(error_reason(&e))
+                            }
+                        },
+                        Err(MsmError::SegmentOpen { source, .. })
+                            if source
+                                .downcast_ref::<io::Error>()
+                                .is_some_and(|it| it.kind() == io::ErrorKind::NotFound) =>
+                        {
+                            Outcome::Missing
+                        }
+                        Err(e) => Outcome::Unreadable(error_reason(&e)),
+                    };
+                    if let Outcome::Found { name, .. } = &outcome {
+                        referenced.insert(name.clone());
+                    }
+                    outcomes.insert(name.clone(), outcome);
+                }
+                segments.entry(name).or_insert_with(|| track.monster_name());
+            }
+        }
+        islands.push((world, None, segments));
+    }
+
+    let sample_rate = most_common(outcomes.values().filter_map(|it| match it {
+        Outcome::Found { sample_rate, .. } => Some(*sample_rate),
+        _ => None,
+    }))
+    .unwrap_or(44100);
+    let channels = most_common(outcomes.values().filter_map(|it| match it {
+        Outcome::Found { channels, .. } => Some(*channels),
+        _ => None,
+    }))
+    .unwrap_or(1);
+
+    let islands = islands
+        .into_iter()
+        .map(|(world, error, segments)| {
+            let issues = segments
+                .iter()
+                .filter_map(|(segment, monster)| {
+                    let (file, problem) = match &outcomes[segment] {
+                        Outcome::Found {
+                            name,
+                            sample_rate: rate,
+                            channels: count,
+                        } if *rate != sample_rate || *count != channels => (
+                            name.clone(),
+                            Problem::Format {
+                                sample_rate: *rate,
+                                channels: *count,
+                            },
+                        ),
+                        Outcome::Found { .. } => return None,
+                        Outcome::Missing => (segment.clone(), Problem::Missing),
+                        Outcome::Unreadable(reason) => {
+                            (segment.clone(), Problem::Unreadable(reason.clone()))
+                        }
+                    };
+                    Some(Issue {
+                        file,
+                        monster: monster.clone(),
+                        problem,
+                    })
+                })
+                .collect();
+            IslandReport {
+                world,
+                error,
+                samples: segments.len(),
+                issues,
+            }
+        })
+        .collect();
+
+    Ok(CheckReport {
+        islands,
+        sample_rate,
+        channels,
+        unreferenced: file_names
+            .into_iter()
+            .filter(|it| !referenced.contains(it) && !ignored.contains(it))
+            .collect(),
+        ignored,
+    })
+}
+
+/// The innermost cause of an error, which is more useful than the path in a table of files
+fn error_reason(error: &MsmError) -> String {
+    match error.source() {
+        Some(source) => source.to_string(),
+        None => error.to_string(),
+    }
+}
+
+fn most_common<T: Copy + Eq + std::hash::Hash + Ord>(values: impl Iterator<Item = T>) -> Option<T> {
+    let mut counts: HashMap<T, usize> = HashMap::new();
+    for value in values {
+        *counts.entry(value).or_default() += 1;
+    }
+    // Ties are broken by the value so the result doesn't depend on the hash order
+    counts
+        .into_iter()
+        .max_by_key(|(value, count)| (*count, std::cmp::Reverse(*value)))
+        .map(|(value, _)| value)
+}
+
+/// Prints a table of problems for each island, followed by the unreferenced files
+pub fn print_report(report: &CheckReport) {
+    for island in report.islands.iter() {
+        let name = crate::island_name(&island.world)
+            .map_or_else(|_| format!("World {}", island.world), |it| it.to_string());
+        if let Some(error) = &island.error {
+            cprintln!("\x1b[1m{}\x1b[22m (world{}.mid)", name, island.world);
+            cprintln!("  \x1b[31m{}\x1b[0m", error);
+            continue;
+        }
+        cprintln!(
+            "\x1b[1m{}\x1b[22m (world{}.mid): {} segments",
+            name,
+            island.world,
+            island.samples
+        );
+        if island.issues.is_empty() {
+            cprintln!("  \x1b[32mAll segments found\x1b[0m");
+            continue;
+        }
+
+        let rows: Vec<(&str, &str, &str, String)> = island
+            .issues
+            .iter()
+            .map(|issue| match &issue.problem {
+                Problem::Missing => (
+                    "31",
+                    "missing",
+                    issue.monster.as_str(),
+                    format!("{}.wav/.ogg", issue.file),
+                ),
+                Problem::Unreadable(reason) => (
+                    "31",
+                    "unreadable",
+                    issue.monster.as_str(),
+                    format!("{} ({})", issue.file, reason),
+                ),
+                Problem::Format {
+                    sample_rate,
+                    channels,
+                } => (
+                    "33",
+                    "format",
+                    issue.monster.as_str(),
+                    format!(
+                        "{} ({} Hz, {} channel{})",
+                        issue.file,
+                        sample_rate,
+                        channels,
+                        if *channels == 1 { "" } else { "s" }
+                    ),
+                ),
+            })
+            .collect();
+        let monster_width = rows
+            .iter()
+            .map(|it| it.2.len())
+            .chain(["Monster".len()])
+            .max()
+            .unwrap();
+        cprintln!(
+            "  \x1b[90m{: <10}  {: <monster_width$}  File\x1b[0m",
+            "Status",
+            "Monster"
+        );
+        for (color, status, monster, file) in rows {
+            cprintln!(
+                "  \x1b[{}m{: <10}\x1b[0m  {: <monster_width$}  {}",
+                color,
+                status,
+                monster,
+                file
+            );
+        }
+    }
+
+    if !report.ignored.is_empty() {
+        cprintln!(
+            "\x1b[1mIgnored MIDI files\x1b[22m ({}), no known island:",
+            report.ignored.len()
+        );
+        for name in report.ignored.iter() {
+            cprintln!("  \x1b[90m{}\x1b[0m", name);
+        }
+    }
+    if !report.unreferenced.is_empty() {
+        cprintln!(
+            "\x1b[1mUnreferenced files\x1b[22m ({}):",
+            report.unreferenced.len()
+        );
+        for name in report.unreferenced.iter() {
+            cprintln!("  \x1b[90m{}\x1b[0m", name);
+        }
+    }
+
+    let formats = report
+        .islands
+        .iter()
+        .flat_map(|it| it.issues.iter())
+        .filter(|it| matches!(it.problem, Problem::Format { .. }))
+        .map(|it| it.file.as_str())
+        .collect::<BTreeSet<&str>>()
+        .len();
+    if formats > 0 {
+        cprintln!(
+            "\x1b[33m{} segments differ from the usual {} Hz with {} channel{}\x1b[0m",
+            formats,
+            report.sample_rate,
+            report.channels,
+            if report.channels == 1 { "" } else { "s" }
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use serde_json::Value;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// The color of a monster in `monster_names.json`, either an xterm 256-color palette index or an
+/// RGB value written as `#rrggbb`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MonsterColor {
+    Xterm(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl MonsterColor {
+    /// Color of monsters missing from `monster_names.json` or without a valid color
+    pub const UNKNOWN: MonsterColor = MonsterColor::Xterm(7);
+
+    /// Reads the `color` field of a monster in `monster_names.json`
+    pub fn from_monster(monster_data: Option<&Value>) -> MonsterColor {
+        match monster_data.map(|it| &it["color"]) {
+            Some(Value::Number(index)) => index
+                .as_u64()
+                .and_then(|it| u8::try_from(it).ok())
+                .map_or(Self::UNKNOWN, MonsterColor::Xterm),
+            Some(Value::String(hex)) => {
+                from_hex(hex).map_or(Self::UNKNOWN, |(r, g, b)| MonsterColor::Rgb(r, g, b))
+            }
+            _ => Self::UNKNOWN,
+        }
+    }
+
+    pub fn rgb(&self) -> (u8, u8, u8) {
+        match *self {
+            MonsterColor::Xterm(index) => xterm_to_rgb(index),
+            MonsterColor::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+
+    /// The palette index, or the closest one for RGB colors
+    pub fn xterm(&self) -> u8 {
+        match *self {
+            MonsterColor::Xterm(index) => index,
+            MonsterColor::Rgb(r, g, b) => rgb_to_xterm((r, g, b)),
+        }
+    }
+
+    /// The parameters of the escape sequence setting this as the foreground color, e.g. `38;5;135`.
+    /// RGB colors are only written as such if `truecolor` is set, see [`truecolor_supported`]
+    pub fn foreground(&self, truecolor: bool) -> String {
+        match *self {
+            MonsterColor::Rgb(r, g, b) if truecolor => format!("38;2;{};{};{}", r, g, b),
+            _ => format!("38;5;{}", self.xterm()),
+        }
+    }
+}
+
+/// Whether the terminal can show 24-bit colors, as announced by the `COLORTERM` environment
+/// variable
+pub fn truecolor_supported() -> bool {
+    matches!(
+        std::env::var("COLORTERM").as_deref(),
+        Ok("truecolor") | Ok("24bit")
+    )
+}
+
+/// When the escape sequences for colors and bold text are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    /// Only if the output is a terminal and `NO_COLOR` is not set
+    Auto,
+    Always,
+    Never,
+}
+
+/// The [`ColorChoice`] of the whole program, set once at startup
+static CHOICE: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+
+/// Sets whether [`stdout_enabled`] and [`stderr_enabled`] allow colors
+pub fn set_choice(choice: ColorChoice) {
+    CHOICE.store(choice as u8, Ordering::Relaxed);
+}
+
+fn enabled(is_terminal: bool) -> bool {
+    match CHOICE.load(Ordering::Relaxed) {
+        choice if choice == ColorChoice::Always as u8 => true,
+        choice if choice == ColorChoice::Never as u8 => false,
+        _ => is_terminal && std::env::var_os("NO_COLOR").is_none_or(|it| it.is_empty()),
+    }
+}
+
+/// Whether colors may be written to stdout
+pub fn stdout_enabled() -> bool {
+    enabled(std::io::stdout().is_terminal())
+}
+
+/// Whether colors may be written to stderr
+pub fn stderr_enabled() -> bool {
+    enabled(std::io::stderr().is_terminal())
+}
+
+/// Removes the escape sequences for colors and text styles from `text`. Other escape sequences,
+/// e.g. for clearing a line, are kept
+pub fn strip(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find("\x1b[") {
+        out += &rest[..index];
+        let sequence = &rest[index + 2..];
+        match sequence.find(|it: char| !it.is_ascii_digit() && it != ';') {
+            Some(end) if sequence[end..].starts_with('m') => rest = &sequence[end + 1..],
+            _ => {
+                out += "\x1b[";
+                rest = sequence;
+            }
+        }
+    }
+    out + rest
+}
+
+/// `text` as it should be written to stdout, see [`stdout_enabled`]
+pub fn for_stdout(text: String) -> String {
+    if stdout_enabled() {
+        text
+    } else {
+        strip(&text)
+    }
+}
+
+/// `text` as it should be written to stderr, see [`stderr_enabled`]
+pub fn for_stderr(text: String) -> String {
+    if stderr_enabled() {
+        text
+    } else {
+        strip(&text)
+    }
+}
+
+/// Converts an xterm 256-color palette index to its RGB value
+pub fn xterm_to_rgb(index: u8) -> (u8, u8, u8) {
+    const BASIC: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (128, 0, 0),
+        (0, 128, 0),
+        (128, 128, 0),
+        (0, 0, 128),
+        (128, 0, 128),
+        (0, 128, 128),
+        (192, 192, 192),
+        (128, 128, 128),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (0, 0, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+    match index {
+        0..=15 => BASIC[index as usize],
+        16..=231 => {
+            let index = index - 16;
+            (
+                CUBE_LEVELS[(index / 36) as usize],
+                CUBE_LEVELS[(index / 6 % 6) as usize],
+                CUBE_LEVELS[(index % 6) as usize],
+            )
+        }
+        _ => {
+            let level = 8 + (index - 232) * 10;
+            (level, level, level)
+        }
+    }
+}
+
+/// Parses an RGB color written as `#rrggbb`
+pub fn from_hex(hex: &str) -> Option<(u8, u8, u8)> {
+    let digits = hex
+        .strip_prefix('#')
+        .filter(|it| it.len() == 6 && it.chars().all(|it| it.is_ascii_hexdigit()))?;
+    let channel = |index: usize| u8::from_str_radix(&digits[index..index + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// Formats an RGB color as `#rrggbb`
+pub fn to_hex((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// The xterm 256-color palette index closest to an RGB value. The 16 basic colors are skipped, as
+/// many terminal themes change them
+pub fn rgb_to_xterm((r, g, b): (u8, u8, u8)) -> u8 {
+    (16..=255)
+        .min_by_key(|index| {
+            let (r2, g2, b2) = xterm_to_rgb(*index);
+            [(r, r2), (g, g2), (b, b2)]
+                .iter()
+                .map(|(a, b)| (*a as i32 - *b as i32).pow(2))
+                .sum::<i32>()
+        })
+        .unwrap()
+}
+
+/// Mixes an RGB color with black for negative `amount`s and with white for positive ones. An
+/// `amount` of -1 or 1 gives pure black or white
+pub fn shade((r, g, b): (u8, u8, u8), amount: f64) -> (u8, u8, u8) {
+    let target = if amount < 0.0 { 0.0 } else { 255.0 };
+    let amount = amount.abs().min(1.0);
+    let mix = |channel: u8| (channel as f64 + (target - channel as f64) * amount).round() as u8;
+    (mix(r), mix(g), mix(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hex() {
+        assert_eq!(from_hex("#a05cF0"), Some((0xa0, 0x5c, 0xf0)));
+        assert_eq!(from_hex("#000000"), Some((0, 0, 0)));
+        assert_eq!(to_hex((0xa0, 0x5c, 0xf0)), "#a05cf0");
+        for invalid in [
+            "",
+            "#",
+            "a05cf0",
+            "#a05cf",
+            "#a05cf00",
+            "#a05cfg",
+            "#+a05cf",
+            "#a05c\u{e9}",
+        ] {
+            assert_eq!(from_hex(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn xterm_round_trip() {
+        // The 6x6x6 cube and the grayscale ramp, the 16 basic colors are never chosen
+        for index in 16..=255 {
+            assert_eq!(rgb_to_xterm(xterm_to_rgb(index)), index);
+        }
+        assert_eq!(rgb_to_xterm(xterm_to_rgb(9)), 196);
+    }
+
+    #[test]
+    fn foreground() {
+        let rgb = MonsterColor::Rgb(0xa0, 0x5c, 0xf0);
+        assert_eq!(rgb.foreground(true), "38;2;160;92;240");
+        assert_eq!(rgb.foreground(false), "38;5;135");
+        let xterm = MonsterColor::Xterm(34);
+        assert_eq!(xterm.foreground(true), "38;5;34");
+        assert_eq!(xterm.foreground(false), "38;5;34");
+    }
+
+    #[test]
+    fn monster_colors() {
+        let monster = |color: Value| Some(serde_json::json!({ "name": "Tweedle", "color": color }));
+        assert_eq!(
+            MonsterColor::from_monster(monster("#a05cf0".into()).as_ref()),
+            MonsterColor::Rgb(0xa0, 0x5c, 0xf0)
+        );
+        assert_eq!(
+            MonsterColor::from_monster(monster(135.into()).as_ref()),
+            MonsterColor::Xterm(135)
+        );
+        assert_eq!(
+            MonsterColor::from_monster(monster(256.into()).as_ref()),
+            MonsterColor::UNKNOWN
+        );
+        assert_eq!(
+            MonsterColor::from_monster(monster("red".into()).as_ref()),
+            MonsterColor::UNKNOWN
+        );
+        assert_eq!(MonsterColor::from_monster(None), MonsterColor::UNKNOWN);
+    }
+}
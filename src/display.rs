@@ -1,80 +1,410 @@
-use crate::{parse::SongData, ISLAND_NAMES};
+use crate::color::{self, MonsterColor};
+use crate::parse::{SongData, Track, BEATS_PER_BAR};
+use crate::{ceprintln, cprintln, MsmError, Result};
 use serde_json::{Map, Value};
+use std::str;
 
-pub fn display(data: &SongData, world: &str, monster_names: &Map<String, Value>) {
-    println!(
-        "\n\x1b[1m{}\x1b[0m - {}bpm {:02}:{:0>5.2}m",
-        ISLAND_NAMES[world.parse::<usize>().unwrap()],
-        data.bpm,
-        (data.duration as u64) / 60,
-        data.duration - ((data.duration as u64) / 60 * 60) as f64
+/// Width of the monster name column in front of each track, e.g. `      Tweedle: `
+const LABEL_WIDTH: usize = 19;
+/// Terminal width used when it can't be determined, e.g. when the output is piped
+const DEFAULT_WIDTH: usize = 80;
+/// Zoom levels tried by [`Zoom::Auto`], from the finest to the coarsest
+const AUTO_ZOOMS: [f64; 5] = [1.0, 2.0, 4.0, 8.0, 16.0];
+/// Characters filling the blocks of each monster when colors are disabled, see [`glyph`]
+const GLYPHS: [char; 12] = ['#', '=', '*', '+', 'o', '%', '~', 'x', '@', '/', '&', '$'];
+/// How far the shades of `--phrases shades` go towards black and white in total, see
+/// [`color::shade`]
+const SHADE_RANGE: f64 = 0.7;
+/// Tolerance when mapping beats to cells. Part starts converted from MIDI ticks to seconds and
+/// back rarely land exactly on a beat
+const CELL_EPSILON: f64 = 1e-3;
+
+/// How many beats one character of the terminal timeline covers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zoom {
+    /// The finest of 1, 2, 4, 8 or 16 beats per cell at which the song fits the terminal width.
+    /// Songs too long even at 16 beats per cell are wrapped
+    Auto,
+    /// A power of two between 1/4 and 16
+    BeatsPerCell(f64),
+}
+
+impl str::FromStr for Zoom {
+    type Err = MsmError;
+
+    /// Parses `auto` or a number of beats per cell like `2` or `0.5`
+    fn from_str(raw: &str) -> Result<Zoom> {
+        if raw == "auto" {
+            return Ok(Zoom::Auto);
+        }
+        raw.parse::<f64>()
+            .ok()
+            .filter(|it| (0.25..=16.0).contains(it) && it.log2().fract() == 0.0)
+            .map(Zoom::BeatsPerCell)
+            .ok_or_else(|| MsmError::InvalidZoom(raw.to_owned()))
+    }
+}
+
+/// How the phrases of a monster, i.e. its different sounds, are told apart in the terminal
+/// timeline
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhraseMode {
+    /// Each block is labeled with its sound id, as far as it fits
+    Labels,
+    /// Each phrase of a monster gets its own shade of the monster's color
+    Shades,
+}
+
+/// Settings of the terminal timeline
+#[derive(Debug, Clone)]
+pub struct DisplayOptions {
+    pub zoom: Zoom,
+    /// Width of the terminal in characters. Tracks longer than this are wrapped into several
+    /// systems, like the lines of sheet music
+    pub width: usize,
+    /// Repeat the ruler below the tracks, which helps with islands with many monsters
+    pub footer: bool,
+    /// Tell the phrases of each monster apart and list them below the timeline
+    pub phrases: Option<PhraseMode>,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        DisplayOptions {
+            zoom: Zoom::Auto,
+            width: terminal_width(),
+            footer: false,
+            phrases: None,
+        }
+    }
+}
+
+/// The width of the terminal on stdout, falling back to `$COLUMNS` and then 80 characters
+pub fn terminal_width() -> usize {
+    terminal_size::terminal_size()
+        .map(|(width, _)| width.0 as usize)
+        .or_else(|| std::env::var("COLUMNS").ok()?.parse().ok())
+        .filter(|it| *it > 0)
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+/// The tempo of the song in bpm, or the range of tempos if it changes
+pub fn bpm_label(data: &SongData) -> String {
+    let min_bpm = data
+        .tempo
+        .iter()
+        .map(|it| it.bpm())
+        .fold(f64::MAX, f64::min);
+    let max_bpm = data
+        .tempo
+        .iter()
+        .map(|it| it.bpm())
+        .fold(f64::MIN, f64::max);
+    if min_bpm.round() == max_bpm.round() {
+        format!("{:.0}bpm", min_bpm)
+    } else {
+        format!("{:.0}-{:.0}bpm", min_bpm, max_bpm)
+    }
+}
+
+/// Formats a duration in seconds as `mm:ss.ss`
+pub fn duration_label(duration: f64) -> String {
+    format!(
+        "{:02}:{:0>5.2}",
+        (duration as u64) / 60,
+        duration - ((duration as u64) / 60 * 60) as f64
+    )
+}
+
+/// The cell containing a position given in beats
+fn cell_at(beats: f64, zoom: f64) -> usize {
+    (beats / zoom + CELL_EPSILON).floor().max(0.0) as usize
+}
+
+/// The character filling `cell` of the monster in `row` when colors are disabled. Each row
+/// alternates two glyphs, the first 12 rows repeating a single one, so the patterns of the first
+/// 144 rows are all different
+fn glyph(row: usize, cell: usize) -> char {
+    let first = row % GLYPHS.len();
+    let second = (first + row / GLYPHS.len()) % GLYPHS.len();
+    GLYPHS[[first, second][cell % 2]]
+}
+
+/// Writes `label` into `line` at `cell`, unless it would touch the previous label or run past
+/// the end of the line
+fn place_label(line: &mut [char], cell: usize, label: &str) {
+    let end = cell + label.chars().count();
+    if end > line.len()
+        || line[cell.saturating_sub(1)..end]
+            .iter()
+            .any(|it| *it != ' ')
+    {
+        return;
+    }
+    for (char, label_char) in line[cell..end].iter_mut().zip(label.chars()) {
+        *char = label_char;
+    }
+}
+
+/// A distinct sound of a monster
+struct Phrase {
+    id: String,
+    /// How many times the sound is played
+    count: usize,
+    /// Color of the blocks playing the sound
+    color: MonsterColor,
+}
+
+/// The distinct sounds of a track, sorted by id. Ids are compared as numbers where possible, so
+/// dipster sound `10` comes after `9`. With `shades` the phrases are spread from darker to lighter
+/// shades of `color`
+fn phrases(track: &Track, color: MonsterColor, shades: bool) -> Vec<Phrase> {
+    let mut phrases: Vec<Phrase> = vec![];
+    for sound in track.parts.iter().filter_map(|it| it.sound.as_ref()) {
+        match phrases.iter_mut().find(|it| &it.id == sound) {
+            Some(phrase) => phrase.count += 1,
+            None => phrases.push(Phrase {
+                id: sound.clone(),
+                count: 1,
+                color,
+            }),
+        }
+    }
+    phrases.sort_by_key(|it| (it.id.parse::<u32>().unwrap_or(u32::MAX), it.id.clone()));
+    if shades && phrases.len() > 1 {
+        let last = (phrases.len() - 1) as f64;
+        for (index, phrase) in phrases.iter_mut().enumerate() {
+            let amount = (index as f64 / last - 0.5) * SHADE_RANGE;
+            let (r, g, b) = color::shade(color.rgb(), amount);
+            phrase.color = MonsterColor::Rgb(r, g, b);
+        }
+    }
+    phrases
+}
+
+/// The bar numbers and the times in `mm:ss` of the markers in the cells `range`
+fn ruler(
+    data: &SongData,
+    zoom: f64,
+    marker_cells: usize,
+    range: std::ops::Range<usize>,
+) -> [String; 2] {
+    let mut bars = vec![' '; range.len()];
+    let mut times = vec![' '; range.len()];
+    for cell in range.clone().filter(|it| it % marker_cells == 0) {
+        let beats = cell as f64 * zoom;
+        let seconds = data.seconds_at(beats).min(data.duration) as u64;
+        place_label(
+            &mut bars,
+            cell - range.start,
+            &format!("{}", beats as usize / BEATS_PER_BAR + 1),
+        );
+        place_label(
+            &mut times,
+            cell - range.start,
+            &format!("{:02}:{:02}", seconds / 60, seconds % 60),
+        );
+    }
+    [bars.into_iter().collect(), times.into_iter().collect()]
+}
+
+/// The finest of [`AUTO_ZOOMS`] at which `beats` fit into `available` cells, or the coarsest one
+fn auto_zoom(beats: f64, available: usize) -> f64 {
+    AUTO_ZOOMS
+        .into_iter()
+        .find(|zoom| cell_at(beats, *zoom) + 2 <= available)
+        .unwrap_or(AUTO_ZOOMS[AUTO_ZOOMS.len() - 1])
+}
+
+pub fn display(data: &SongData, monster_names: &Map<String, Value>, options: &DisplayOptions) {
+    cprintln!(
+        "\n\x1b[1m{}\x1b[0m - {} {}m",
+        data.island_name,
+        bpm_label(data),
+        duration_label(data.duration),
     );
 
-    let beats_per_second = data.bpm as f64 / 60.0;
-    let track_length = ((data.duration * beats_per_second).round() as usize / 4) + 2;
+    // One character is left free, as some terminals wrap when the last column is written
+    let available = options.width.saturating_sub(LABEL_WIDTH + 1);
+    let beats = data.beats_at(data.duration);
+    let zoom = match options.zoom {
+        Zoom::BeatsPerCell(zoom) => zoom,
+        Zoom::Auto => auto_zoom(beats, available),
+    };
+    // A marker is drawn every 4 cells, but at least once per bar
+    let marker_cells = 4.max((BEATS_PER_BAR as f64 / zoom) as usize);
+    let track_length = cell_at(beats, zoom) + 2;
+    // Systems always start on a marker
+    let system_length = (available / marker_cells).max(1) * marker_cells;
 
     let mut tracks = data.tracks.clone();
     tracks.sort_unstable_by_key(|it| {
-        let monster_name = if it.dipster.is_none() {
-            it.name.clone()
-        } else {
-            format!("Q{:02}_Monster", it.dipster.unwrap())
-        };
+        let monster_name = it.monster_name();
         monster_names
             .keys()
             .position(|e| e == &monster_name)
             .unwrap_or_else(|| {
-                eprintln!(
+                ceprintln!(
                     "\x1b[31;1m{}\x1b[22m not found while sorting\x1b[0m",
                     it.name
                 );
                 usize::MAX
             })
     });
-    for track in tracks.iter() {
-        let monster_name = if track.dipster.is_none() {
-            track.name.clone()
-        } else {
-            format!("Q{:02}_Monster", track.dipster.unwrap())
-        };
-        let monster_data: Map<String, Value> = monster_names
-            .get(&monster_name)
-            .unwrap_or(&serde_json::json!({
-                "name": "Unknown",
-                "color": 7,
-            }))
-            .as_object()
-            .unwrap()
-            .clone();
-        print!("  {: >15}: ", monster_data["name"].as_str().unwrap());
-
-        let mut track_chars: Vec<String> = vec!["".to_string(); track_length];
-        track_chars = track_chars
-            .iter()
-            .enumerate()
-            .map(|(index, _)| {
-                if index % 4 == 0 {
-                    "\u{258F}".to_string()
+    // Without colors, shades can't be told apart, so the phrases are labeled instead
+    let colors = color::stdout_enabled();
+    let truecolor = color::truecolor_supported();
+    let labels = match options.phrases {
+        Some(PhraseMode::Labels) => true,
+        Some(PhraseMode::Shades) => !colors,
+        None => false,
+    };
+    let (grid_marker, part_marker) = if colors {
+        ("\u{258F}", "\u{258F}")
+    } else {
+        ("|", "[")
+    };
+
+    let mut rows: Vec<(String, Vec<String>)> = vec![];
+    let mut legend: Vec<(String, Vec<Phrase>)> = vec![];
+    for (row, track) in tracks.iter().enumerate() {
+        let monster_data = monster_names.get(&track.monster_name());
+        let name = monster_data
+            .and_then(|it| it["name"].as_str())
+            .unwrap_or("Unknown")
+            .to_owned();
+        let color = MonsterColor::from_monster(monster_data);
+
+        let phrases = phrases(track, color, options.phrases == Some(PhraseMode::Shades));
+
+        let mut track_chars: Vec<String> = (0..track_length)
+            .map(|index| {
+                if index % marker_cells == 0 {
+                    grid_marker.to_string()
                 } else {
                     " ".to_string()
                 }
             })
             .collect();
         for part in track.parts.iter() {
-            if part.sound.is_none() {
-                continue;
+            let sound = match &part.sound {
+                Some(sound) => sound,
+                None => continue,
+            };
+            let start = cell_at(data.beats_at(part.start), zoom);
+            let length = cell_at(
+                data.beats_at(part.start + part.duration) - data.beats_at(part.start),
+                zoom,
+            )
+            .max(1);
+            let color = phrases
+                .iter()
+                .find(|it| &it.id == sound)
+                .map_or(color, |it| it.color);
+            let mut label: Vec<char> = if labels {
+                sound.chars().collect()
+            } else {
+                vec![]
+            };
+            // The label follows the start marker if there is room, otherwise it replaces it. Too
+            // long labels keep their end, which is what differs between ids like `01` and `02`
+            let label_start = (label.len() < length) as usize;
+            if label.len() > length {
+                label.drain(..label.len() - length);
             }
-            let start = (part.start * beats_per_second).round() as usize / 4;
-            let duration = (part.duration * beats_per_second).round() as usize / 4;
-            let char_range = start..(start + if duration == 0 { 1 } else { duration });
-            for index in char_range {
-                track_chars[index] = format!(
-                    "\x1b[38;5;{};7m{}\x1b[0m",
-                    monster_data["color"],
-                    if index == start { "\u{258F}" } else { " " }
-                );
+            for (offset, char) in track_chars.iter_mut().skip(start).take(length).enumerate() {
+                let text = match offset.checked_sub(label_start).and_then(|it| label.get(it)) {
+                    Some(label_char) => label_char.to_string(),
+                    None if offset == 0 => part_marker.to_string(),
+                    None if colors => " ".to_string(),
+                    None => glyph(row, start + offset).to_string(),
+                };
+                *char = if colors {
+                    format!("\x1b[{};7m{}\x1b[0m", color.foreground(truecolor), text)
+                } else {
+                    text
+                };
             }
         }
-        println!("{}", track_chars.join(""));
+        rows.push((name.clone(), track_chars));
+        legend.push((name, phrases));
+    }
+
+    let wrapped = track_length > system_length;
+    for system_start in (0..track_length).step_by(system_length) {
+        let system_end = (system_start + system_length).min(track_length);
+        let [bars, times] = ruler(data, zoom, marker_cells, system_start..system_end);
+        let print_ruler = || {
+            cprintln!("\x1b[90m  {: >15}: {}\x1b[0m", "Bar", bars);
+            cprintln!("\x1b[90m  {: >15}: {}\x1b[0m", "Time", times);
+        };
+        print_ruler();
+        for (name, track_chars) in rows.iter() {
+            cprintln!(
+                "  {: >15}: {}",
+                name,
+                track_chars[system_start..system_end].join("")
+            );
+        }
+        if options.footer {
+            print_ruler();
+        }
+        if wrapped && system_end < track_length {
+            cprintln!();
+        }
+    }
+
+    if !colors {
+        cprintln!("\nLegend (each monster fills its sounds with its own pattern)");
+        for (row, (name, _)) in legend.iter().enumerate() {
+            let pattern: String = (1..5).map(|cell| glyph(row, cell)).collect();
+            cprintln!("  {: >15}: {}{}", name, part_marker, pattern);
+        }
+    }
+
+    if options.phrases.is_some() {
+        cprintln!("\n\x1b[1mPhrases\x1b[0m");
+        for (name, phrases) in legend.iter() {
+            let entries: Vec<String> = phrases
+                .iter()
+                .map(|phrase| match labels {
+                    false => format!(
+                        "\x1b[{}m\u{2588}\x1b[0m {} \u{d7}{}",
+                        phrase.color.foreground(truecolor),
+                        phrase.id,
+                        phrase.count
+                    ),
+                    true if colors => format!("{} \u{d7}{}", phrase.id, phrase.count),
+                    true => format!("{} x{}", phrase.id, phrase.count),
+                })
+                .collect();
+            cprintln!("  {: >15}: {}", name, entries.join("  "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_glyph_patterns() {
+        let patterns: Vec<(char, char)> = (0..GLYPHS.len().pow(2))
+            .map(|row| (glyph(row, 0), glyph(row, 1)))
+            .collect();
+        for (row, pattern) in patterns.iter().enumerate() {
+            assert!(!patterns[..row].contains(pattern), "row {}", row);
+        }
+    }
+
+    #[test]
+    fn auto_zoom_fits_long_songs() {
+        // Two cells are needed past the end of the song
+        assert_eq!(auto_zoom(20.0, 60), 1.0);
+        assert_eq!(auto_zoom(100.0, 60), 2.0);
+        assert_eq!(auto_zoom(384.0, 60), 8.0);
+        assert_eq!(auto_zoom(900.0, 60), 16.0);
+        // Wrapped at the coarsest zoom
+        assert_eq!(auto_zoom(2000.0, 60), 16.0);
     }
 }
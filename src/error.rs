@@ -0,0 +1,369 @@
+use std::{error::Error, fmt, io};
+
+/// Every way the song creator can fail. Each variant corresponds to one of the exit codes used by
+/// the `msm` binary, see [`MsmError::exit_code`].
+#[derive(Debug)]
+pub enum MsmError {
+    /// The island MIDI file could not be read
+    MidiOpen { path: String, source: io::Error },
+    /// The island MIDI file is not valid MIDI
+    MidiParse { path: String, source: midly::Error },
+    /// A MIDI track has no track name
+    MissingTrackName,
+    /// A MIDI track name is not valid UTF-8, given with invalid bytes replaced
+    InvalidTrackName(String),
+    /// The timing of the MIDI file is not metrical
+    NonMetricalTiming { path: String },
+    /// No tempo event was found in the MIDI file
+    MissingTempo,
+    /// The game file archive could not be opened
+    ArchiveOpen {
+        path: String,
+        source: zip::result::ZipError,
+    },
+    /// The files of the music folder could not be listed
+    AssetList { path: String, source: io::Error },
+    /// A sound segment could not be opened
+    SegmentOpen {
+        path: String,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The output file could not be created or written
+    OutputWrite { source: hound::Error },
+    /// The MIDI export could not be written
+    MidiWrite { path: String, source: io::Error },
+    /// A sound segment could not be decoded
+    SegmentRead {
+        path: String,
+        source: lewton::VorbisError,
+    },
+    /// The output directory could not be created
+    CreateDir { path: String, source: io::Error },
+    /// The given island is neither a valid number nor a valid name
+    InvalidIsland(String),
+    /// The tempo is not between 0.5 and 2
+    InvalidTempo(f32),
+    /// The number of repeats is not between 1 and 100
+    InvalidRepeat(u8),
+    /// The pitch shift is not between -12 and 12 semitones
+    InvalidPitch(f32),
+    /// The output sample rate is not between 8000 and 192000 Hz
+    InvalidSampleRate(u32),
+    /// A `--start`/`--end` position is neither seconds nor `bar:beat`
+    InvalidPosition(String),
+    /// The `--start`/`--end` window is empty or starts after the end of the song. Holds the start
+    /// and end in seconds
+    InvalidWindow(f64, f64),
+    /// The fade-out length is negative
+    InvalidFade(f32),
+    /// A `--zoom` value is neither `auto` nor a supported number of beats per cell
+    InvalidZoom(String),
+    /// `--json print` was combined with `--all`, which would mix the documents of several islands
+    JsonPrintAll,
+    /// A pan position is not of the form `<monster>=<position>` with a position between -1 and 1
+    InvalidPan(String),
+    /// A decoded segment could not be saved to the cache directory
+    CacheWrite { path: String, source: io::Error },
+    /// Some islands of `--all` could not be created
+    BatchFailed { failed: usize, total: usize },
+    /// An exclude/include list file could not be opened
+    ListOpen { path: String, source: io::Error },
+    /// An exclude/include list file could not be read
+    ListRead { path: String, source: io::Error },
+    /// An entry of an exclude/include list is not a valid RegEx
+    ListRegex { index: usize, source: regex::Error },
+    /// The timeline graphic could not be written
+    TimelineWrite { path: String, source: io::Error },
+    /// The JSON export could not be written
+    JsonWrite { path: String, source: io::Error },
+    /// A FLAC or Ogg Vorbis file could not be written
+    AudioWrite { path: String, source: io::Error },
+    /// `msm check` found segments or MIDI files that are missing or unreadable. Holds their number
+    CheckFailed(usize),
+    /// The island profile file could not be read
+    ProfileOpen { path: String, source: io::Error },
+    /// The island profile file is not a valid profile
+    ProfileParse { path: String, message: String },
+    /// A user specified path is not valid UTF-8. Holds a description of the path
+    InvalidUtf8Path(&'static str),
+}
+
+impl MsmError {
+    /// The exit code the `msm` binary uses for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MsmError::MidiOpen { .. } => 2,
+            MsmError::MidiParse { .. } => 3,
+            MsmError::MissingTrackName => 4,
+            MsmError::InvalidTrackName(_) => 9,
+            MsmError::NonMetricalTiming { .. } => 5,
+            MsmError::MissingTempo => 6,
+            MsmError::ArchiveOpen { .. } => 7,
+            MsmError::AssetList { .. } => 8,
+            MsmError::SegmentOpen { .. } => 10,
+            MsmError::OutputWrite { .. } => 11,
+            MsmError::SegmentRead { .. } => 12,
+            MsmError::MidiWrite { .. } => 13,
+            MsmError::CreateDir { .. } => 14,
+            MsmError::InvalidIsland(_) => 15,
+            MsmError::InvalidTempo(_) => 16,
+            MsmError::InvalidRepeat(_) => 17,
+            MsmError::InvalidPan(_) => 18,
+            MsmError::TimelineWrite { .. } => 19,
+            MsmError::JsonWrite { .. } => 20,
+            MsmError::InvalidPitch(_) => 21,
+            MsmError::InvalidSampleRate(_) => 22,
+            MsmError::AudioWrite { .. } => 23,
+            MsmError::CheckFailed(_) => 24,
+            MsmError::BatchFailed { .. } => 25,
+            MsmError::CacheWrite { .. } => 26,
+            MsmError::InvalidPosition(_) => 27,
+            MsmError::InvalidWindow(..) => 28,
+            MsmError::InvalidFade(_) => 29,
+            MsmError::ListOpen { .. } => 30,
+            MsmError::ListRead { .. } => 31,
+            MsmError::ListRegex { .. } => 32,
+            MsmError::ProfileOpen { .. } => 33,
+            MsmError::ProfileParse { .. } => 34,
+            MsmError::InvalidZoom(_) => 35,
+            MsmError::JsonPrintAll => 36,
+            MsmError::InvalidUtf8Path(_) => 42,
+        }
+    }
+}
+
+impl MsmError {
+    /// The error message with the relevant values highlighted in bold, as shown by the `msm`
+    /// binary
+    pub fn highlighted(&self) -> impl fmt::Display + '_ {
+        Highlighted(self)
+    }
+
+    /// Writes the error message, wrapping the relevant values in bold SGR codes if `bold` is set
+    fn write_message(&self, f: &mut fmt::Formatter<'_>, bold: bool) -> fmt::Result {
+        match self {
+            MsmError::MidiOpen { path, source } => {
+                write!(f, "File {} could not be opened: {}", Em(path, bold), source)
+            }
+            MsmError::MidiParse { path, source } => write!(
+                f,
+                "Error while parsing {} as MIDI: {}",
+                Em(path, bold),
+                source
+            ),
+            MsmError::MissingTrackName => {
+                write!(f, "Malformed MIDI track: track name could not be found")
+            }
+            MsmError::InvalidTrackName(name) => write!(
+                f,
+                "Malformed MIDI track: track name {} is not valid UTF-8",
+                Em(name, bold)
+            ),
+            MsmError::NonMetricalTiming { path } => {
+                write!(f, "Timing of MIDI file {} is not metrical", Em(path, bold))
+            }
+            MsmError::MissingTempo => write!(f, "Malformed MIDI file: tempo not specified"),
+            MsmError::ArchiveOpen { path, source } => write!(
+                f,
+                "Error while opening archive {}: {}",
+                Em(path, bold),
+                source
+            ),
+            MsmError::AssetList { path, source } => write!(
+                f,
+                "Error while listing the files of {}: {}",
+                Em(path, bold),
+                source
+            ),
+            MsmError::SegmentOpen { path, source } => {
+                write!(f, "Error while opening {}: {}", Em(path, bold), source)
+            }
+            MsmError::OutputWrite { source } => {
+                write!(f, "Error while creating output file: {}", source)
+            }
+            MsmError::SegmentRead { path, source } => {
+                write!(f, "Error while reading {}: {}", Em(path, bold), source)
+            }
+            MsmError::MidiWrite { path, source } => write!(
+                f,
+                "Error while writing MIDI file {}: {}",
+                Em(path, bold),
+                source
+            ),
+            MsmError::CreateDir { path, source } => write!(
+                f,
+                "Error while creating directory {}: {}",
+                Em(path, bold),
+                source
+            ),
+            MsmError::InvalidIsland(island) => write!(
+                f,
+                "The specified island {} is not valid. Use `msm --list-islands` for a list of valid islands",
+                Em(island, bold)
+            ),
+            MsmError::InvalidTempo(tempo) => write!(
+                f,
+                "The specified tempo {} is not between 0.5 and 2",
+                Em(tempo, bold)
+            ),
+            MsmError::InvalidRepeat(repeat) => write!(
+                f,
+                "The specified repeats {} is not between 1 and 100",
+                Em(repeat, bold)
+            ),
+            MsmError::InvalidPitch(pitch) => write!(
+                f,
+                "The specified pitch shift {} is not between -12 and 12 semitones",
+                Em(pitch, bold)
+            ),
+            MsmError::InvalidSampleRate(sample_rate) => write!(
+                f,
+                "The specified sample rate {} is not between 8000 and 192000 Hz",
+                Em(sample_rate, bold)
+            ),
+            MsmError::InvalidPosition(position) => write!(
+                f,
+                "The specified position {} is neither seconds nor of the form <bar>:<beat>",
+                Em(position, bold)
+            ),
+            MsmError::InvalidWindow(start, end) => write!(
+                f,
+                "The specified window from {} to {} is empty or outside of the song",
+                Em(format_args!("{:.2}s", start), bold),
+                Em(format_args!("{:.2}s", end), bold)
+            ),
+            MsmError::InvalidFade(fade) => write!(
+                f,
+                "The specified fade-out {} is not a positive number of seconds",
+                Em(fade, bold)
+            ),
+            MsmError::InvalidZoom(zoom) => write!(
+                f,
+                "The specified zoom {} is neither auto nor a power of two between 0.25 and 16 beats",
+                Em(zoom, bold)
+            ),
+            MsmError::InvalidPan(pan) => write!(
+                f,
+                "The specified pan {} is not of the form <monster>=<position> with a position between -1 and 1",
+                Em(pan, bold)
+            ),
+            MsmError::JsonPrintAll => write!(
+                f,
+                "`--json print` can't be combined with `--all`. Use `--json save` instead"
+            ),
+            MsmError::TimelineWrite { path, source } => write!(
+                f,
+                "Error while writing timeline {}: {}",
+                Em(path, bold),
+                source
+            ),
+            MsmError::JsonWrite { path, source } => write!(
+                f,
+                "Error while writing JSON file {}: {}",
+                Em(path, bold),
+                source
+            ),
+            MsmError::AudioWrite { path, source } => write!(
+                f,
+                "Error while writing audio file {}: {}",
+                Em(path, bold),
+                source
+            ),
+            MsmError::CheckFailed(count) => write!(
+                f,
+                "{} segments or MIDI files are missing or could not be read",
+                Em(count, bold)
+            ),
+            MsmError::CacheWrite { path, source } => write!(
+                f,
+                "Error while writing cache file {}: {}",
+                Em(path, bold),
+                source
+            ),
+            MsmError::BatchFailed { failed, total } => write!(
+                f,
+                "{} islands could not be created",
+                Em(format_args!("{} of {}", failed, total), bold)
+            ),
+            MsmError::ListOpen { path, source } => {
+                write!(f, "Error while opening file {}: {}", Em(path, bold), source)
+            }
+            MsmError::ListRead { path, source } => {
+                write!(f, "Error while reading file {}: {}", Em(path, bold), source)
+            }
+            MsmError::ListRegex { index, source } => write!(
+                f,
+                "Error while parsing exclude/include list at index {}: {}",
+                Em(index, bold),
+                source
+            ),
+            MsmError::ProfileOpen { path, source } => write!(
+                f,
+                "Error while opening profile {}: {}",
+                Em(path, bold),
+                source
+            ),
+            MsmError::ProfileParse { path, message } => {
+                write!(f, "Invalid profile {}: {}", Em(path, bold), message)
+            }
+            MsmError::InvalidUtf8Path(what) => {
+                write!(f, "The specified {} is not valid UTF-8", what)
+            }
+        }
+    }
+}
+
+impl fmt::Display for MsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_message(f, false)
+    }
+}
+
+/// See [`MsmError::highlighted`]
+struct Highlighted<'a>(&'a MsmError);
+
+impl fmt::Display for Highlighted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.write_message(f, true)
+    }
+}
+
+/// A value of an error message, shown in bold if the flag is set
+struct Em<T>(T, bool);
+
+impl<T: fmt::Display> fmt::Display for Em<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1 {
+            write!(f, "\x1b[1m{}\x1b[22m", self.0)
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+impl Error for MsmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MsmError::MidiOpen { source, .. }
+            | MsmError::MidiWrite { source, .. }
+            | MsmError::CreateDir { source, .. }
+            | MsmError::TimelineWrite { source, .. }
+            | MsmError::JsonWrite { source, .. }
+            | MsmError::AudioWrite { source, .. }
+            | MsmError::AssetList { source, .. }
+            | MsmError::CacheWrite { source, .. }
+            | MsmError::ListOpen { source, .. }
+            | MsmError::ListRead { source, .. }
+            | MsmError::ProfileOpen { source, .. } => Some(source),
+            MsmError::MidiParse { source, .. } => Some(source),
+            MsmError::ArchiveOpen { source, .. } => Some(source),
+            MsmError::SegmentOpen { source, .. } => Some(source.as_ref()),
+            MsmError::OutputWrite { source } => Some(source),
+            MsmError::SegmentRead { source, .. } => Some(source),
+            MsmError::ListRegex { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T, E = MsmError> = std::result::Result<T, E>;
@@ -0,0 +1,98 @@
+//! JSON export of the fully resolved song timeline for downstream tools.
+//!
+//! The document has the following layout. Fields are only ever added within the same
+//! [`SCHEMA_VERSION`], any other change increments it.
+//!
+//! ```json
+//! {
+//!     "version": 1,
+//!     "island": { "number": 1, "name": "Plant Island" },
+//!     "duration": 192.0,
+//!     "bpm": 120.0,
+//!     "tempo": [{ "start": 0.0, "start_beat": 0.0, "microseconds_per_beat": 500000 }],
+//!     "tracks": [{
+//!         "monster": "A_Monster",
+//!         "display_name": "Tweedle",
+//!         "color": 135,
+//!         "rgb": "#af5fff",
+//!         "name": "A_Monster",
+//!         "dipster": null,
+//!         "parts": [{ "start": 0.0, "duration": 4.0, "offset": 0.0, "sound": "01" }]
+//!     }]
+//! }
+//! ```
+//!
+//! All times are in seconds. `offset` is where in its sound a part begins, which is only not 0 for
+//! parts cut by `--start`. `name` is the name used for the sound files and `monster` the key in
+//! `monster_names.json`, which only differ for dipsters. `color` is the xterm 256-color palette
+//! index of the monster, or the closest one if it has an RGB color, and `rgb` its exact color.
+//! Tracks are sorted like in the timeline.
+
+use crate::color::{self, MonsterColor};
+use crate::parse::SongData;
+use crate::{MsmError, Result};
+use serde_json::{json, Map, Value};
+
+/// Version of the JSON document layout
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Converts a song to the versioned JSON document described in the [module docs](self)
+pub fn to_json(data: &SongData, monster_names: &Map<String, Value>) -> Value {
+    // Already validated by `parse`
+    let island = data.island.parse::<u8>().ok();
+    let mut tracks = data.tracks.clone();
+    crate::sort_tracks(&mut tracks, monster_names);
+
+    let tracks: Vec<Value> = tracks
+        .iter()
+        .map(|track| {
+            let monster_name = track.monster_name();
+            let monster_data = monster_names.get(&monster_name);
+            let mut out = Map::new();
+            out.insert("monster".to_owned(), json!(monster_name));
+            out.insert(
+                "display_name".to_owned(),
+                monster_data.map_or(json!("Unknown"), |it| it["name"].clone()),
+            );
+            let color = MonsterColor::from_monster(monster_data);
+            out.insert("color".to_owned(), json!(color.xterm()));
+            out.insert("rgb".to_owned(), json!(color::to_hex(color.rgb())));
+            if let Value::Object(fields) = serde_json::to_value(track).unwrap() {
+                out.extend(fields);
+            }
+            Value::Object(out)
+        })
+        .collect();
+
+    json!({
+        "version": SCHEMA_VERSION,
+        "island": { "number": island, "name": data.island_name },
+        "duration": data.duration,
+        "bpm": data.bpm(),
+        "tempo": data.tempo,
+        "tracks": tracks,
+    })
+}
+
+/// Writes the JSON document of a song to `{out_path}/{world}_{island}.json`
+pub fn write_json(
+    data: &SongData,
+    world: &str,
+    out_path: &str,
+    monster_names: &Map<String, Value>,
+) -> Result<()> {
+    if let Err(source) = std::fs::create_dir_all(out_path) {
+        return Err(MsmError::CreateDir {
+            path: out_path.to_owned(),
+            source,
+        });
+    }
+    let path = format!(
+        "{}/{}_{}.json",
+        out_path,
+        world,
+        data.island_name.replace(' ', "-")
+    );
+    let content = serde_json::to_string_pretty(&to_json(data, monster_names)).unwrap();
+    std::fs::write(&path, content + "\n").map_err(|source| MsmError::JsonWrite { path, source })
+}
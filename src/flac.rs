@@ -0,0 +1,494 @@
+//! A small FLAC encoder using fixed predictors and partitioned Rice coding.
+
+use crate::write::{quantize, Repeated};
+use std::io::{self, Write};
+
+const BLOCK_SIZE: usize = 4096;
+/// Highest Rice partition order that is tried
+const MAX_PARTITION_ORDER: u32 = 8;
+
+/// Writes a song as a FLAC stream with the given bit depth. `tags` are stored as Vorbis comments
+pub fn write_flac<W: Write>(
+    mut writer: W,
+    song: &Repeated,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    tags: &[(&str, String)],
+) -> io::Result<()> {
+    let total = song.len;
+
+    writer.write_all(b"fLaC")?;
+
+    // STREAMINFO
+    let mut info = BitWriter::default();
+    info.write(BLOCK_SIZE as u64, 16);
+    info.write(BLOCK_SIZE as u64, 16);
+    info.write(0, 24);
+    info.write(0, 24);
+    info.write(sample_rate as u64, 20);
+    info.write(song.channel_count() as u64 - 1, 3);
+    info.write(bits_per_sample as u64 - 1, 5);
+    info.write(total as u64, 36);
+    // Unknown MD5 signature
+    for _ in 0..4 {
+        info.write(0, 32);
+    }
+    write_metadata_block(&mut writer, 0, false, &info.bytes)?;
+
+    // VORBIS_COMMENT, using little endian lengths unlike the rest of the format
+    let vendor = concat!("msm ", env!("CARGO_PKG_VERSION"));
+    let mut comments = vec![];
+    comments.extend((vendor.len() as u32).to_le_bytes());
+    comments.extend(vendor.as_bytes());
+    comments.extend((tags.len() as u32).to_le_bytes());
+    for (key, value) in tags {
+        let comment = format!("{}={}", key, value);
+        comments.extend((comment.len() as u32).to_le_bytes());
+        comments.extend(comment.as_bytes());
+    }
+    write_metadata_block(&mut writer, 4, true, &comments)?;
+
+    let mut block: Vec<Vec<i64>> = vec![Vec::with_capacity(BLOCK_SIZE); song.channel_count()];
+    for (frame_number, start) in (0..total).step_by(BLOCK_SIZE).enumerate() {
+        let end = (start + BLOCK_SIZE).min(total);
+        for (channel, block) in block.iter_mut().enumerate() {
+            block.clear();
+            block.extend(
+                (start..end)
+                    .map(|index| quantize(song.sample(channel, index), bits_per_sample) as i64),
+            );
+        }
+        writer.write_all(&encode_frame(&block, frame_number as u64, bits_per_sample))?;
+    }
+    writer.flush()
+}
+
+fn write_metadata_block<W: Write>(
+    writer: &mut W,
+    block_type: u8,
+    last: bool,
+    data: &[u8],
+) -> io::Result<()> {
+    writer.write_all(&[block_type | if last { 0x80 } else { 0 }])?;
+    writer.write_all(&(data.len() as u32).to_be_bytes()[1..])?;
+    writer.write_all(data)
+}
+
+/// Encodes one frame. Stereo frames use whichever of left/right, left/side, side/right and
+/// mid/side is the smallest
+fn encode_frame(block: &[Vec<i64>], frame_number: u64, bits_per_sample: u32) -> Vec<u8> {
+    let block_size = block[0].len();
+
+    let (channel_assignment, subframes) = if block.len() == 2 {
+        let side: Vec<i64> = block[0].iter().zip(&block[1]).map(|(l, r)| l - r).collect();
+        let mid: Vec<i64> = block[0]
+            .iter()
+            .zip(&block[1])
+            .map(|(l, r)| (l + r) >> 1)
+            .collect();
+        let left = (block[0].clone(), plan_subframe(&block[0], bits_per_sample));
+        let right = (block[1].clone(), plan_subframe(&block[1], bits_per_sample));
+        let side_plan = plan_subframe(&side, bits_per_sample + 1);
+        let mid_plan = plan_subframe(&mid, bits_per_sample);
+        let side = (side, side_plan);
+        let mid = (mid, mid_plan);
+
+        let candidates = [
+            (1, left.1.cost + right.1.cost),
+            (8, left.1.cost + side.1.cost),
+            (9, side.1.cost + right.1.cost),
+            (10, mid.1.cost + side.1.cost),
+        ];
+        let assignment = candidates.iter().min_by_key(|it| it.1).unwrap().0;
+        let subframes = match assignment {
+            8 => vec![(left, bits_per_sample), (side, bits_per_sample + 1)],
+            9 => vec![(side, bits_per_sample + 1), (right, bits_per_sample)],
+            10 => vec![(mid, bits_per_sample), (side, bits_per_sample + 1)],
+            _ => vec![(left, bits_per_sample), (right, bits_per_sample)],
+        };
+        (assignment, subframes)
+    } else {
+        (
+            block.len() as u64 - 1,
+            block
+                .iter()
+                .map(|channel| {
+                    (
+                        (channel.clone(), plan_subframe(channel, bits_per_sample)),
+                        bits_per_sample,
+                    )
+                })
+                .collect(),
+        )
+    };
+
+    let mut out = BitWriter::default();
+    // Sync code and fixed block size strategy
+    out.write(0xfff8, 16);
+    let block_size_code = if block_size == BLOCK_SIZE { 12 } else { 7 };
+    out.write(block_size_code, 4);
+    // Sample rate from STREAMINFO
+    out.write(0, 4);
+    out.write(channel_assignment, 4);
+    out.write(
+        match bits_per_sample {
+            16 => 4,
+            24 => 6,
+            _ => 0,
+        },
+        3,
+    );
+    out.write(0, 1);
+    for byte in utf8_number(frame_number) {
+        out.write(byte as u64, 8);
+    }
+    if block_size_code == 7 {
+        out.write(block_size as u64 - 1, 16);
+    }
+    let crc = crc8(&out.bytes);
+    out.write(crc as u64, 8);
+
+    for ((samples, plan), bits) in subframes.iter() {
+        write_subframe(&mut out, samples, *bits, plan);
+    }
+    out.align();
+    let crc = crc16(&out.bytes);
+    out.write(crc as u64, 16);
+    out.bytes
+}
+
+#[derive(Debug)]
+enum SubframeKind {
+    Constant,
+    Verbatim,
+    Fixed {
+        order: usize,
+        partition_order: u32,
+        parameters: Vec<u32>,
+    },
+}
+
+#[derive(Debug)]
+struct SubframePlan {
+    kind: SubframeKind,
+    /// Size of the subframe in bits
+    cost: u64,
+}
+
+/// Finds the smallest encoding of a subframe
+fn plan_subframe(samples: &[i64], bits: u32) -> SubframePlan {
+    if samples.iter().all(|it| *it == samples[0]) {
+        return SubframePlan {
+            kind: SubframeKind::Constant,
+            cost: 8 + bits as u64,
+        };
+    }
+    let mut best = SubframePlan {
+        kind: SubframeKind::Verbatim,
+        cost: 8 + bits as u64 * samples.len() as u64,
+    };
+    for order in 0..=4.min(samples.len() - 1) {
+        let residuals = fixed_residuals(samples, order);
+        let (partition_order, parameters, residual_cost) =
+            plan_partitions(&residuals, samples.len(), order);
+        let cost = 8 + order as u64 * bits as u64 + residual_cost;
+        if cost < best.cost {
+            best = SubframePlan {
+                kind: SubframeKind::Fixed {
+                    order,
+                    partition_order,
+                    parameters,
+                },
+                cost,
+            };
+        }
+    }
+    best
+}
+
+fn write_subframe(out: &mut BitWriter, samples: &[i64], bits: u32, plan: &SubframePlan) {
+    match &plan.kind {
+        SubframeKind::Constant => {
+            out.write(0, 8);
+            out.write_signed(samples[0], bits);
+        }
+        SubframeKind::Verbatim => {
+            out.write(0b10, 8);
+            for sample in samples {
+                out.write_signed(*sample, bits);
+            }
+        }
+        SubframeKind::Fixed {
+            order,
+            partition_order,
+            parameters,
+        } => {
+            out.write((0b1000 | *order as u64) << 1, 8);
+            for sample in samples[..*order].iter() {
+                out.write_signed(*sample, bits);
+            }
+            // Partitioned Rice coding with 4 bit parameters, or 5 bit parameters if needed
+            let wide = parameters.iter().any(|it| *it > 14);
+            let parameter_bits = if wide { 5 } else { 4 };
+            out.write(wide as u64, 2);
+            out.write(*partition_order as u64, 4);
+            let residuals = fixed_residuals(samples, *order);
+            let partition_len = samples.len() >> partition_order;
+            let mut residuals = residuals.iter();
+            for (partition, parameter) in parameters.iter().enumerate() {
+                out.write(*parameter as u64, parameter_bits);
+                let count = if partition == 0 {
+                    partition_len - order
+                } else {
+                    partition_len
+                };
+                for residual in residuals.by_ref().take(count) {
+                    let value = zigzag(*residual);
+                    out.write_unary(value >> parameter);
+                    out.write(value & ((1 << parameter) - 1), *parameter);
+                }
+            }
+        }
+    }
+}
+
+/// Residuals of the fixed polynomial predictor of the given order, starting after the warm-up
+/// samples
+fn fixed_residuals(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|n| {
+            let s = |offset: usize| samples[n - offset];
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+/// Chooses the Rice partition order and parameters. Returns the partition order, the parameter
+/// of each partition and the total size of the residual in bits
+fn plan_partitions(residuals: &[i64], block_size: usize, order: usize) -> (u32, Vec<u32>, u64) {
+    // The highest order that evenly divides the block and leaves room for the warm-up samples
+    let mut max_order = 0;
+    while max_order < MAX_PARTITION_ORDER
+        && block_size.is_multiple_of(1 << (max_order + 1))
+        && block_size >> (max_order + 1) > order
+    {
+        max_order += 1;
+    }
+
+    // Sums of the zigzag encoded residuals of the finest partitions, merged for coarser orders
+    let partition_len = block_size >> max_order;
+    let mut sums: Vec<(u64, u64)> = vec![(0, 0); 1 << max_order];
+    for (index, residual) in residuals.iter().enumerate() {
+        let partition = (index + order) / partition_len;
+        sums[partition].0 += zigzag(*residual);
+        sums[partition].1 += 1;
+    }
+
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for partition_order in (0..=max_order).rev() {
+        let parameters: Vec<(u32, u64)> = sums
+            .iter()
+            .map(|(sum, count)| {
+                (0..=30)
+                    .map(|parameter| {
+                        (
+                            parameter,
+                            count * (parameter as u64 + 1) + (sum >> parameter),
+                        )
+                    })
+                    .min_by_key(|it| it.1)
+                    .unwrap()
+            })
+            .collect();
+        let parameter_bits = if parameters.iter().any(|it| it.0 > 14) {
+            5
+        } else {
+            4
+        };
+        let cost = 6 + parameters
+            .iter()
+            .map(|it| parameter_bits + it.1)
+            .sum::<u64>();
+        if best.as_ref().is_none_or(|it| cost < it.2) {
+            best = Some((
+                partition_order,
+                parameters.iter().map(|it| it.0).collect(),
+                cost,
+            ));
+        }
+        sums = sums
+            .chunks(2)
+            .map(|it| {
+                (
+                    it.iter().map(|it| it.0).sum(),
+                    it.iter().map(|it| it.1).sum(),
+                )
+            })
+            .collect();
+    }
+    best.unwrap()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Encodes a frame number in the extended UTF-8 scheme used by FLAC
+fn utf8_number(value: u64) -> Vec<u8> {
+    if value < 0x80 {
+        return vec![value as u8];
+    }
+    let mut continuation = vec![];
+    let mut value = value;
+    let mut first_bits = 6;
+    while value >= 1 << first_bits {
+        continuation.push(0x80 | (value & 0x3f) as u8);
+        value >>= 6;
+        first_bits -= 1;
+    }
+    let count = continuation.len() as u32 + 1;
+    let prefix = (0xff00u16 >> count) as u8;
+    let mut out = vec![prefix | value as u8];
+    out.extend(continuation.into_iter().rev());
+    out
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Writes bits most significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        // `count` stays below 8 and no more than 36 bits are written at once, so this fits
+        self.buffer = (self.buffer << bits) | (value & ((1 << bits) - 1));
+        self.count += bits;
+        while self.count >= 8 {
+            self.count -= 8;
+            self.bytes.push((self.buffer >> self.count) as u8);
+        }
+        self.buffer &= (1 << self.count) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64 & ((1 << bits) - 1), bits);
+    }
+
+    /// Writes `value` zeros followed by a one
+    fn write_unary(&mut self, value: u64) {
+        let mut value = value;
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    /// Pads with zeros to the next byte boundary
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write::test_signal as signal;
+    use claxon::FlacReader;
+
+    const TAGS: [(&str, &str); 2] = [("TITLE", "Plant Island"), ("MONSTERS", "Mammott, Noggin")];
+
+    /// Encodes `channels` and checks that they decode to the same quantized samples
+    fn round_trip(channels: &[Vec<f32>], bits: u32) {
+        let song = Repeated::from_channels(channels, 44100);
+        let tags: Vec<(&str, String)> = TAGS.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        let mut bytes = vec![];
+        write_flac(&mut bytes, &song, 44100, bits, &tags).unwrap();
+
+        let mut reader = FlacReader::new(&bytes[..]).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.channels as usize, channels.len());
+        assert_eq!(info.bits_per_sample, bits);
+        assert_eq!(info.samples, Some(channels[0].len() as u64));
+        assert_eq!(reader.tags().collect::<Vec<_>>(), TAGS);
+
+        let decoded: Vec<i32> = reader.samples().map(|it| it.unwrap()).collect();
+        let expected: Vec<i32> = (0..channels[0].len())
+            .flat_map(|index| channels.iter().map(move |it| quantize(it[index], bits)))
+            .collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn mono_16_bit() {
+        round_trip(&signal(1, 10000), 16);
+    }
+
+    #[test]
+    fn stereo_16_bit() {
+        round_trip(&signal(2, 10000), 16);
+    }
+
+    #[test]
+    fn mono_24_bit() {
+        round_trip(&signal(1, 10000), 24);
+    }
+
+    #[test]
+    fn stereo_24_bit() {
+        round_trip(&signal(2, 10000), 24);
+    }
+
+    #[test]
+    fn odd_length() {
+        // Shorter than one block, and a last block of 1 sample
+        round_trip(&signal(2, 1001), 16);
+        round_trip(&signal(1, 2 * BLOCK_SIZE + 1), 24);
+    }
+
+    #[test]
+    fn silence() {
+        round_trip(&[vec![0.0; 5000], vec![0.0; 5000]], 16);
+    }
+
+    #[test]
+    fn clipped_samples() {
+        round_trip(&[vec![1.5, -1.5, 1.0, -1.0, 0.0]], 16);
+    }
+}
@@ -0,0 +1,128 @@
+//! Library behind the `msm` binary. Parses the island MIDI files of My Singing Monsters, renders
+//! the full songs from the game's sound segments and displays their timelines.
+
+use parse::Track;
+use serde_json::{Map, Value};
+
+pub mod assets;
+pub mod cache;
+pub mod check;
+pub mod color;
+pub mod display;
+pub mod error;
+pub mod export;
+mod flac;
+pub mod lists;
+pub mod midi;
+pub mod mix;
+pub mod parse;
+pub mod profile;
+pub mod resample;
+pub mod stretch;
+pub mod timeline;
+mod vorbis;
+pub mod write;
+
+pub use error::{MsmError, Result};
+
+/// Like `println!`, but leaves out colors when they are disabled for stdout, see
+/// [`color::stdout_enabled`]
+#[macro_export]
+macro_rules! cprintln {
+    () => {
+        println!()
+    };
+    ($($arg:tt)*) => {
+        println!("{}", $crate::color::for_stdout(format!($($arg)*)))
+    };
+}
+
+/// Like `print!`, but leaves out colors when they are disabled for stdout
+#[macro_export]
+macro_rules! cprint {
+    ($($arg:tt)*) => {
+        print!("{}", $crate::color::for_stdout(format!($($arg)*)))
+    };
+}
+
+/// Like `eprintln!`, but leaves out colors when they are disabled for stderr, see
+/// [`color::stderr_enabled`]
+#[macro_export]
+macro_rules! ceprintln {
+    ($($arg:tt)*) => {
+        eprintln!("{}", $crate::color::for_stderr(format!($($arg)*)))
+    };
+}
+
+pub const ISLAND_NAMES: [&str; 24] = [
+    "",
+    "Plant Island",
+    "Cold Island",
+    "Air Island",
+    "Water Island",
+    "Earth Island",
+    "Gold Island",
+    "Ethereal Island",
+    "Shugabush Island",
+    "Tribal Island",
+    "Wublin Island",
+    "",
+    "Celestial Island",
+    "Fire Haven",
+    "Fire Oasis",
+    "Psychic Island",
+    "Faerie Island",
+    "Bone Island",
+    "Light Island",
+    "Magical Sanctum",
+    "",
+    "Seasonal Shanty",
+    "Amber Island",
+    "Mythical Island",
+];
+
+/// Parses the embedded `monster_names.json`
+pub fn monster_names() -> Map<String, Value> {
+    let raw_monster_names: Value = serde_json::from_reader(json_comments::StripComments::new(
+        &include_bytes!("res/monster_names.json")[..],
+    ))
+    .unwrap();
+    raw_monster_names.as_object().unwrap().clone()
+}
+
+/// Sorts tracks in the order their monsters appear in `monster_names.json`. Unknown monsters are
+/// placed last
+pub fn sort_tracks(tracks: &mut [Track], monster_names: &Map<String, Value>) {
+    tracks.sort_by_key(|it| {
+        let monster_name = it.monster_name();
+        monster_names
+            .keys()
+            .position(|e| e == &monster_name)
+            .unwrap_or(usize::MAX)
+    });
+}
+
+/// The name of the island of `world{world}.mid`, e.g. `"03"`
+pub fn island_name(world: &str) -> Result<&'static str> {
+    world
+        .parse::<usize>()
+        .ok()
+        .and_then(|it| ISLAND_NAMES.get(it))
+        .filter(|it| !it.is_empty())
+        .copied()
+        .ok_or_else(|| MsmError::InvalidIsland(world.to_owned()))
+}
+
+/// Resolves an island number or name to its island number
+pub fn parse_island(raw_island: &str) -> Result<u8> {
+    if let Ok(num) = raw_island.parse::<u8>() {
+        if num < ISLAND_NAMES.len() as u8 && !ISLAND_NAMES[num as usize].is_empty() {
+            return Ok(num);
+        }
+    } else if !raw_island.is_empty() {
+        if let Some(num) = ISLAND_NAMES.iter().position(|it| *it == raw_island) {
+            return Ok(num as u8);
+        }
+    }
+    Err(MsmError::InvalidIsland(raw_island.to_owned()))
+}
@@ -1,3 +1,4 @@
+use crate::{MsmError, Result};
 use regex::Regex;
 use serde_json::{Map, Value};
 use std::{collections::HashMap, fs::File, io::Read};
@@ -15,22 +16,15 @@ pub fn get_name_map(json: &Map<String, Value>) -> HashMap<String, Vec<String>> {
     out
 }
 
-pub fn read_list_file(path: String) -> Vec<String> {
-    let mut file = File::open(&path).unwrap_or_else(|e| {
-        eprintln!(
-            "\x1b[31mError while opening file \x1b[1m{}\x1b[22m: {}",
-            path, e
-        );
-        std::process::exit(30);
-    });
+pub fn read_list_file(path: String) -> Result<Vec<String>> {
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(source) => return Err(MsmError::ListOpen { path, source }),
+    };
     let mut content = String::new();
-    file.read_to_string(&mut content).unwrap_or_else(|e| {
-        eprintln!(
-            "\x1b[31mError while reading file \x1b[1m{}\x1b[22m: {}",
-            path, e
-        );
-        std::process::exit(31);
-    });
+    if let Err(source) = file.read_to_string(&mut content) {
+        return Err(MsmError::ListRead { path, source });
+    }
 
     let ignore_regex = Regex::new(r"^[\t ]*(|#.*)$").unwrap();
     let mut out: Vec<String> = vec![];
@@ -41,10 +35,13 @@ pub fn read_list_file(path: String) -> Vec<String> {
         out.push(line.to_owned());
     }
 
-    out
+    Ok(out)
 }
 
-pub fn parse_list(list: Vec<String>, monster_names: &HashMap<String, Vec<String>>) -> Vec<Regex> {
+pub fn parse_list(
+    list: Vec<String>,
+    monster_names: &HashMap<String, Vec<String>>,
+) -> Result<Vec<Regex>> {
     let mut out: Vec<Regex> = vec![];
     for (index, line) in list
         .iter()
@@ -58,10 +55,10 @@ pub fn parse_list(list: Vec<String>, monster_names: &HashMap<String, Vec<String>
         })
         .enumerate()
     {
-        out.push(Regex::new(format!("^({})$", line.join("|")).as_str()).unwrap_or_else(|e| {
-            eprintln!("\x1b[31mError while parsing exclude/include list at index \x1b[1m{}\x1b[22m: {}", index, e);
-            std::process::exit(32);
-        }));
+        out.push(
+            Regex::new(format!("^({})$", line.join("|")).as_str())
+                .map_err(|source| MsmError::ListRegex { index, source })?,
+        );
     }
-    out
+    Ok(out)
 }
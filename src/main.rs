@@ -1,49 +1,58 @@
+use msm::{
+    assets::{self, AssetSource},
+    cache::SampleCache,
+    ceprintln, check,
+    color::{self, ColorChoice},
+    cprint, cprintln,
+    display::{self, DisplayOptions, PhraseMode, Zoom},
+    export, lists, midi,
+    mix::Mix,
+    parse::{self, Position},
+    profile::Profile,
+    resample::Quality,
+    timeline::{self, TimelineFormat},
+    write::{self, AudioFormat, BitDepth, Log, WriteOptions},
+    MsmError, Result, ISLAND_NAMES,
+};
+use regex::Regex;
 use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
-mod display;
-mod lists;
-mod parse;
-mod write;
-
-pub const ISLAND_NAMES: [&str; 24] = [
-    "",
-    "Plant Island",
-    "Cold Island",
-    "Air Island",
-    "Water Island",
-    "Earth Island",
-    "Gold Island",
-    "Ethereal Island",
-    "Shugabush Island",
-    "Tribal Island",
-    "Wublin Island",
-    "",
-    "Celestial Island",
-    "Fire Haven",
-    "Fire Oasis",
-    "Psychic Island",
-    "Faerie Island",
-    "Bone Island",
-    "Light Island",
-    "Magical Sanctum",
-    "",
-    "Seasonal Shanty",
-    "Amber Island",
-    "Mythical Island",
-];
-
 /// Tool to create all My Singing Monsters songs and timelines from the game files
 #[derive(Debug, StructOpt)]
-#[structopt(author)]
+#[structopt(author, setting = AppSettings::SubcommandsNegateReqs)]
 struct Msm {
-    /// Island numbers or names. Required unless `--list-islands` or `--list-monsters` is used
-    #[structopt(required_unless("list-islands"), required_unless("list-monsters"))]
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    /// Island numbers or names. Required unless `--all`, `--list-islands`, `--list-monsters` or `check` is used
+    #[structopt(
+        required_unless("all"),
+        required_unless("list-islands"),
+        required_unless("list-monsters")
+    )]
     islands: Vec<String>,
 
-    /// Path to MSM data/audio/music [default: "./data/"]
-    #[structopt(short, long)]
+    /// Create the songs of all islands, processing several islands at once
+    ///
+    /// Uses one worker per CPU core. Islands that fail don't stop the others, instead all failures
+    /// are listed at the end. The timeline is not shown in the terminal, but can still be saved with
+    /// `--timeline-format`.
+    #[structopt(short, long, conflicts_with("islands"))]
+    all: bool,
+
+    /// Path to MSM data/audio/music or to the `.obb` file of the Android version [default: "./data/"]
+    ///
+    /// Archives are read directly, so the `.obb` file doesn't have to be extracted first. A `.zip`
+    /// file containing the music files works as well.
+    #[structopt(short, long, global = true)]
     path: Option<PathBuf>,
 
     /// Output folder [default: "./"]
@@ -58,14 +67,60 @@ struct Msm {
     #[structopt(short = "t", long)]
     no_timeline: bool,
 
+    /// Format of the song timeline
+    ///
+    /// `ansi` prints the timeline to the terminal, `svg` and `html` save it as a scalable graphic
+    /// to the output folder.
+    #[structopt(long, default_value = "ansi", possible_values = &["ansi", "svg", "html"])]
+    timeline_format: String,
+
+    /// When to use colors in the terminal
+    ///
+    /// `auto` uses colors if the output is a terminal and the `NO_COLOR` environment variable is
+    /// not set. Without colors the timeline draws each monster with its own pattern of characters.
+    #[structopt(long, default_value = "auto", possible_values = &["auto", "always", "never"], global = true)]
+    color: String,
+
+    /// How many beats one character of the terminal timeline covers
+    ///
+    /// Must be a power of two between 0.25 and 16. `auto` uses the finest of 1, 2, 4, 8 or 16
+    /// beats at which the song fits the terminal width. Songs wider than the terminal even at 16
+    /// beats per cell are wrapped into several systems, each labeled with the bar it starts at.
+    #[structopt(long, default_value = "auto")]
+    zoom: Zoom,
+
+    /// Repeat the bar and time ruler of the terminal timeline below the monsters
+    #[structopt(long)]
+    ruler_footer: bool,
+
+    /// Tell the phrases (sounds) of each monster apart in the terminal timeline
+    ///
+    /// `labels` writes the sound id into each block as far as it fits, `shades` gives each phrase
+    /// of a monster its own shade of the monster's color. Both list the phrases of each monster and
+    /// how often they are played below the timeline.
+    #[structopt(long, possible_values = &["labels", "shades"])]
+    phrases: Option<String>,
+
     /// Suppress creating song wav file
     #[structopt(short = "s", long)]
     no_song: bool,
 
     /// Set the tempo of the song. Just like in-game this will also change the pitch
+    ///
+    /// Use `--time-stretch` to keep the pitch.
     #[structopt(short = "T", long, default_value = "1.0")]
     tempo: f32,
 
+    /// Keep the pitch when changing the tempo with `--tempo`
+    #[structopt(long)]
+    time_stretch: bool,
+
+    /// Shift the pitch of the song by the given semitones without changing its duration
+    ///
+    /// Must be between -12 and 12. Fractional values are allowed.
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    pitch: f32,
+
     /// Show a list of all valid island numbers and their respective names
     #[structopt(short, long)]
     list_islands: bool,
@@ -102,135 +157,551 @@ struct Msm {
     #[structopt(short = "I", long)]
     include_list: Option<PathBuf>,
 
+    /// Path to an island profile file extending the built-in track-name and note-number rules
+    ///
+    /// Uses the same JSON format as the built-in profile (`src/res/island_profile.json`).
+    /// Rules in this file take precedence over the built-in ones, global rules in this file also
+    /// over built-in island rules. Use `keep` to undo a built-in `skip`.
+    #[structopt(short = "P", long, global = true)]
+    profile: Option<PathBuf>,
+
+    /// Only create the song from this position on, given in seconds or as `<bar>:<beat>`
+    ///
+    /// Bars and beats are counted from 1, so `5:1` is the start of the fifth bar. Parts that
+    /// started earlier are cut at this position. Applies to all created files and the timeline.
+    #[structopt(long)]
+    start: Option<Position>,
+
+    /// Only create the song up to this position, given in seconds or as `<bar>:<beat>`
+    ///
+    /// See `--start` for the format. `17:1` ends the song after 16 bars.
+    #[structopt(long)]
+    end: Option<Position>,
+
     /// How many times the song should be repeated
+    ///
+    /// Sounds still playing at the end of a repetition continue into the next one, like when the
+    /// song loops in-game.
     #[structopt(short, long, default_value = "1")]
     repeat: u8,
+
+    /// Let the sounds still playing at the end of the song ring out instead of cutting them off
+    #[structopt(long)]
+    tail: bool,
+
+    /// Fade out the end of the song over the given number of seconds
+    #[structopt(long, default_value = "0")]
+    fade: f32,
+
+    /// Create a stereo song wav file with the monsters spread from left to right
+    #[structopt(short = "S", long)]
+    stereo: bool,
+
+    /// Pan positions of monsters in stereo songs, as `<monster>=<position>`
+    ///
+    /// The position ranges from -1 (left) to 1 (right). Monsters without a specified position are
+    /// spread evenly in the order of `--list-monsters`.
+    /// Use `--list-monsters` for a list of all valid monster names.
+    #[structopt(long)]
+    pan: Vec<String>,
+
+    /// Additionally create one wav file per monster, all with the same length as the song
+    ///
+    /// The files are named after the island number and the monster. They are not affected by
+    /// `--mix`, so together they add up to the unprocessed song.
+    #[structopt(long)]
+    stems: bool,
+
+    /// Additionally create a cleaned standard MIDI file of the song
+    ///
+    /// Tracks are named after the monsters and the note numbers are the sound indices played by
+    /// each monster. Excluded monsters are left out.
+    #[structopt(short = "M", long)]
+    midi_out: bool,
+
+    /// Export the resolved song timeline as JSON for other tools
+    ///
+    /// `print` writes the JSON document to stdout, `save` saves it to the output folder.
+    /// Combine with `--no-timeline` and `--no-song` to only get the JSON document. `print` can't be
+    /// used with `--all`.
+    #[structopt(long, possible_values = &["print", "save"])]
+    json: Option<String>,
+
+    /// How the mix is processed to avoid clipping
+    ///
+    /// `clip` hard clips loud parts like older versions did, `peak` scales the song so its highest
+    /// peak reaches -1 dBFS, `loudness` scales the song to the loudness set by `--lufs` and
+    /// `limit` applies a soft-knee limiter.
+    #[structopt(short, long, default_value = "clip", possible_values = &["clip", "peak", "loudness", "limit"])]
+    mix: String,
+
+    /// Target loudness in LUFS for `--mix loudness`
+    #[structopt(long, default_value = "-14", allow_hyphen_values = true)]
+    lufs: f32,
+
+    /// Quality of the resampling used for segments with other sample rates and for `--tempo` and
+    /// `--pitch`
+    ///
+    /// `low`, `medium` and `high` use increasingly long band-limited filters, `linear` is the
+    /// fastest but adds audible aliasing.
+    #[structopt(long, default_value = "medium", possible_values = &["linear", "low", "medium", "high"])]
+    resample_quality: String,
+
+    /// Sample rate of the created wav files in Hz, e.g. 22050, 44100, 48000 or 96000
+    #[structopt(long, default_value = "44100")]
+    sample_rate: u32,
+
+    /// Bit depth of the created wav files. 32 creates floating point files
+    #[structopt(long, default_value = "16", possible_values = &["16", "24", "32"])]
+    bit_depth: u8,
+
+    /// Folder to keep decoded sound segments in, so later runs don't have to decode them again
+    ///
+    /// Segments are stored uncompressed for each sample rate and resampling quality, so the folder
+    /// can grow large. It is safe to delete it at any time.
+    #[structopt(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// File format of the created songs and stems
+    ///
+    /// FLAC files use 24 bits for `--bit-depth 32`. All formats are tagged with the island name,
+    /// the included monsters, the tempo and the number of repeats.
+    #[structopt(short, long, default_value = "wav", possible_values = &["wav", "flac", "ogg"])]
+    format: String,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Check that the game files in `--path` are complete
+    ///
+    /// Parses every island MIDI file and looks up the sound segment of every sound. Lists missing
+    /// and unreadable segments, segments with an unusual sample rate or channel count and files
+    /// that no island uses. Exits with an error if any segment is missing or unreadable.
+    Check,
 }
 
 fn main() {
-    let msm = Msm::from_args();
+    if let Err(e) = run(Msm::from_args()) {
+        ceprintln!("\x1b[31m{}\x1b[0m", e.highlighted());
+        std::process::exit(e.exit_code());
+    }
+}
+
+/// Converts a user specified path to a `String`, failing if it is not valid UTF-8
+fn path_to_string(path: PathBuf, what: &'static str) -> Result<String> {
+    path.into_os_string()
+        .into_string()
+        .map_err(|_| MsmError::InvalidUtf8Path(what))
+}
+
+/// Parses `<monster>=<position>` pan positions. Monsters may be specified by their display name
+/// as well
+fn parse_pans(
+    raw_pans: &[String],
+    name_map: &HashMap<String, Vec<String>>,
+) -> Result<HashMap<String, f32>> {
+    let mut out = HashMap::new();
+    for raw_pan in raw_pans {
+        let (name, position) = raw_pan
+            .rsplit_once('=')
+            .and_then(|(name, position)| Some((name, position.parse::<f32>().ok()?)))
+            .filter(|(_, position)| (-1.0..=1.0).contains(position))
+            .ok_or_else(|| MsmError::InvalidPan(raw_pan.clone()))?;
+        for name in name_map.get(name).cloned().unwrap_or(vec![name.to_owned()]) {
+            out.insert(name, position);
+        }
+    }
+    Ok(out)
+}
 
+fn run(msm: Msm) -> Result<()> {
+    color::set_choice(match msm.color.as_str() {
+        "always" => ColorChoice::Always,
+        "never" => ColorChoice::Never,
+        _ => ColorChoice::Auto,
+    });
     if msm.list_islands {
-        println!("\x1b[1mList of valid islands:\x1b[0m");
+        cprintln!("\x1b[1mList of valid islands:\x1b[0m");
         for (index, name) in ISLAND_NAMES.iter().enumerate() {
             if name == &"" {
                 continue;
             }
-            println!("  {: >2}: {}", index, name);
+            cprintln!("  {: >2}: {}", index, name);
         }
-        return;
+        return Ok(());
     }
-    let raw_monster_names: Value = serde_json::from_reader(json_comments::StripComments::new(
-        &include_bytes!("res/monster_names.json")[..],
-    ))
-    .unwrap();
-    let monster_names: Map<String, Value> = raw_monster_names.as_object().unwrap().clone();
+    let monster_names = msm::monster_names();
     if msm.list_monsters {
-        println!("\x1b[1mList of monsters:\x1b[0m");
+        cprintln!("\x1b[1mList of monsters:\x1b[0m");
         for (key, data) in monster_names.iter() {
-            println!("  {: <15} -> {}", key, data["name"].as_str().unwrap());
+            cprintln!("  {: <15} -> {}", key, data["name"].as_str().unwrap());
         }
-        return;
+        return Ok(());
     }
 
     if !(0.5..=2.0).contains(&msm.tempo) {
-        eprintln!(
-            "\x1b[31mThe specified tempo \x1b[1m{}\x1b[22m is not between 0.5 and 2",
-            msm.tempo
-        );
-        std::process::exit(16);
+        return Err(MsmError::InvalidTempo(msm.tempo));
+    }
+    if !(-12.0..=12.0).contains(&msm.pitch) {
+        return Err(MsmError::InvalidPitch(msm.pitch));
     }
     if !(1..=100).contains(&msm.repeat) {
-        eprintln!(
-            "\x1b[31mThe specified repeats \x1b[1m{}\x1b[22m is not between 1 and 100",
-            msm.repeat
-        );
-        std::process::exit(17);
-    }
-
-    let data_path: String = msm
-        .path
-        .unwrap_or(PathBuf::from("data"))
-        .to_str()
-        .unwrap_or_else(|| {
-            eprintln!("\x1b[31mThe specified data path is not valid UTF-8\x1b[0m");
-            std::process::exit(42);
-        })
-        .to_owned();
-    let out_path: String = msm
-        .output
-        .unwrap_or(PathBuf::from("."))
-        .to_str()
-        .unwrap_or_else(|| {
-            eprintln!("\x1b[31mThe specified output path is not valid UTF-8\x1b[0m");
-            std::process::exit(42);
-        })
-        .to_owned();
-
-    let exclude_list_path: Option<String> = msm.exclude_list.map(|path| {
-        path.to_str()
-            .unwrap_or_else(|| {
-                eprintln!(
-                    "\x1b[31mThe specified path to the exclude list is not valid UTF-8\x1b[0m"
-                );
-                std::process::exit(42);
-            })
-            .to_owned()
-    });
-    let include_list_path: Option<String> = msm.include_list.map(|path| {
-        path.to_str()
-            .unwrap_or_else(|| {
-                eprintln!(
-                    "\x1b[31mThe specified path to the include list is not valid UTF-8\x1b[0m"
-                );
-                std::process::exit(42);
-            })
-            .to_owned()
-    });
-    let raw_exclude_list = exclude_list_path.map_or(msm.exclude, lists::read_list_file);
-    let raw_include_list = include_list_path.map_or(msm.include, lists::read_list_file);
+        return Err(MsmError::InvalidRepeat(msm.repeat));
+    }
+    if !(msm.fade >= 0.0 && msm.fade.is_finite()) {
+        return Err(MsmError::InvalidFade(msm.fade));
+    }
+    if !(8000..=192000).contains(&msm.sample_rate) {
+        return Err(MsmError::InvalidSampleRate(msm.sample_rate));
+    }
+    if msm.all && msm.json.as_deref() == Some("print") {
+        return Err(MsmError::JsonPrintAll);
+    }
+
+    let data_path = path_to_string(
+        msm.path.clone().unwrap_or(PathBuf::from("data")),
+        "data path",
+    )?;
+    let assets = assets::open(&data_path)?;
+    let out_path = path_to_string(
+        msm.output.clone().unwrap_or(PathBuf::from(".")),
+        "output path",
+    )?;
+
+    let raw_exclude_list = match &msm.exclude_list {
+        Some(path) => {
+            lists::read_list_file(path_to_string(path.clone(), "path to the exclude list")?)?
+        }
+        None => msm.exclude.clone(),
+    };
+    let raw_include_list = match &msm.include_list {
+        Some(path) => {
+            lists::read_list_file(path_to_string(path.clone(), "path to the include list")?)?
+        }
+        None => msm.include.clone(),
+    };
     let name_map = lists::get_name_map(&monster_names);
-    let exclude_list = lists::parse_list(raw_exclude_list, &name_map);
-    let include_list = lists::parse_list(raw_include_list, &name_map);
-
-    for raw_island in msm.islands {
-        let parsed_island = raw_island.parse::<u8>();
-        let island: u8 = if let Ok(num) = parsed_island {
-            if num >= ISLAND_NAMES.len() as u8 || ISLAND_NAMES[num as usize].is_empty() {
-                None
-            } else { Some(num) }
-        } else {
-            let pos = ISLAND_NAMES.iter().position(|it| *it == raw_island);
-            if let Some(num) = pos {
-                if raw_island.is_empty() {
-                    None
-                } else { Some(num as u8) }
-            } else { None }
-        }.unwrap_or_else(|| {
-            eprintln!("\x1b[31mThe specified island \x1b[1m{}\x1b[22m is not valid. Use `msm --list-islands` for a list of valid islands\x1b[0m", raw_island);
-            std::process::exit(15);
-        });
-        let world = format!("{:02}", island);
-
-        let song = parse::parse(
-            format!("{}/world{}.mid", &data_path, world),
+    let exclude_list = lists::parse_list(raw_exclude_list, &name_map)?;
+    let include_list = lists::parse_list(raw_include_list, &name_map)?;
+    let pans = parse_pans(&msm.pan, &name_map)?;
+    let mut profile = Profile::embedded();
+    if let Some(path) = &msm.profile {
+        profile.extend(Profile::from_file(path_to_string(
+            path.clone(),
+            "profile path",
+        )?)?);
+    }
+
+    if let Some(Command::Check) = msm.command {
+        let report = check::check(assets.as_ref(), &profile)?;
+        check::print_report(&report);
+        return match report.failures() {
+            0 => Ok(()),
+            failures => Err(MsmError::CheckFailed(failures)),
+        };
+    }
+
+    let cache = match &msm.cache_dir {
+        Some(path) => SampleCache::with_directory(&path_to_string(path.clone(), "cache folder")?),
+        None => SampleCache::new(),
+    };
+
+    let context = Context {
+        msm: &msm,
+        assets,
+        cache,
+        out_path,
+        exclude_list,
+        include_list,
+        pans,
+        profile,
+        monster_names,
+    };
+    if msm.all {
+        return create_all(&context);
+    }
+    for raw_island in msm.islands.iter() {
+        let island = msm::parse_island(raw_island)?;
+        create_island(&context, island, None)?;
+    }
+
+    Ok(())
+}
+
+/// Everything needed to create the files of an island, shared by all workers of `--all`
+struct Context<'a> {
+    msm: &'a Msm,
+    assets: Box<dyn AssetSource>,
+    cache: SampleCache,
+    out_path: String,
+    exclude_list: Vec<Regex>,
+    include_list: Vec<Regex>,
+    pans: HashMap<String, f32>,
+    profile: Profile,
+    monster_names: Map<String, Value>,
+}
+
+/// Creates the song and all other requested files of an island. With `batch` set the terminal
+/// timeline is skipped and all output goes to that log instead of stdout, as other islands are
+/// processed at the same time
+fn create_island(context: &Context, island: u8, batch: Option<Log>) -> Result<()> {
+    let world = format!("{:02}", island);
+
+    let song = parse::parse(
+        context.assets.as_ref(),
+        &world,
+        &context.profile.island(&world),
+        &context.exclude_list,
+        &context.include_list,
+    )?;
+    let song = match (context.msm.start, context.msm.end) {
+        (None, None) => song,
+        (start, end) => {
+            let start = start.map_or(0.0, |it| song.position_seconds(it));
+            let end = end.map_or(song.duration, |it| {
+                song.position_seconds(it).min(song.duration)
+            });
+            if start >= end {
+                return Err(MsmError::InvalidWindow(start, end));
+            }
+            song.window(start, end)
+        }
+    };
+    let mut options = WriteOptions {
+        verbose: context.msm.verbose,
+        log: batch.clone().unwrap_or_default(),
+        tempo: context.msm.tempo,
+        time_stretch: context.msm.time_stretch,
+        pitch: context.msm.pitch,
+        repeats: context.msm.repeat,
+        tail: context.msm.tail,
+        fade: context.msm.fade,
+        stereo: context.msm.stereo,
+        pans: write::default_pans(&song, &context.monster_names),
+        mix: match context.msm.mix.as_str() {
+            "peak" => Mix::Peak,
+            "loudness" => Mix::Loudness(context.msm.lufs),
+            "limit" => Mix::Limit,
+            _ => Mix::Clip,
+        },
+        resample_quality: match context.msm.resample_quality.as_str() {
+            "linear" => Quality::Linear,
+            "low" => Quality::Low,
+            "high" => Quality::High,
+            _ => Quality::Medium,
+        },
+        sample_rate: context.msm.sample_rate,
+        bit_depth: match context.msm.bit_depth {
+            24 => BitDepth::Int24,
+            32 => BitDepth::Float32,
+            _ => BitDepth::Int16,
+        },
+        format: match context.msm.format.as_str() {
+            "flac" => AudioFormat::Flac,
+            "ogg" => AudioFormat::Ogg,
+            _ => AudioFormat::Wav,
+        },
+    };
+    options.pans.extend(context.pans.clone());
+    if !context.msm.no_song {
+        let report = write::write(
+            &song,
             &world,
-            &exclude_list,
-            &include_list,
-        );
-        if !msm.no_song {
-            write::write(
+            context.assets.as_ref(),
+            &context.cache,
+            &context.out_path,
+            &options,
+            &context.monster_names,
+        )?;
+        if report.clipped_samples > 0 {
+            if options.mix == Mix::Clip {
+                options.log.line(&format!("\x1b[1;33mWarning: {} samples clipped.\x1b[22m Use `--mix` to avoid clipping\x1b[0m", report.clipped_samples));
+            } else if context.msm.verbose {
+                options.log.line(&format!(
+                    "\x1b[90m{} samples would have clipped without `--mix`\x1b[0m",
+                    report.clipped_samples
+                ));
+            }
+        }
+    }
+    if context.msm.stems {
+        write::write_stems(
+            &song,
+            &world,
+            context.assets.as_ref(),
+            &context.cache,
+            &context.out_path,
+            &options,
+            &context.monster_names,
+        )?;
+    }
+    if context.msm.midi_out {
+        midi::write_midi(&song, &world, &context.out_path, &context.monster_names)?;
+    }
+    match context.msm.json.as_deref() {
+        Some("print") => println!(
+            "{}",
+            serde_json::to_string_pretty(&export::to_json(&song, &context.monster_names)).unwrap()
+        ),
+        Some(_) => export::write_json(&song, &world, &context.out_path, &context.monster_names)?,
+        None => {}
+    }
+    if !context.msm.no_timeline {
+        match context.msm.timeline_format.as_str() {
+            "svg" => timeline::write_timeline(
+                &song,
+                &world,
+                &context.out_path,
+                &context.monster_names,
+                TimelineFormat::Svg,
+            )?,
+            "html" => timeline::write_timeline(
                 &song,
                 &world,
-                msm.verbose,
-                &data_path,
-                &out_path,
-                msm.tempo,
-                msm.repeat,
-            );
+                &context.out_path,
+                &context.monster_names,
+                TimelineFormat::Html,
+            )?,
+            _ if batch.is_some() => {}
+            _ => display::display(
+                &song,
+                &context.monster_names,
+                &DisplayOptions {
+                    zoom: context.msm.zoom,
+                    footer: context.msm.ruler_footer,
+                    phrases: match context.msm.phrases.as_deref() {
+                        Some("labels") => Some(PhraseMode::Labels),
+                        Some(_) => Some(PhraseMode::Shades),
+                        None => None,
+                    },
+                    ..DisplayOptions::default()
+                },
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// State of the combined progress display of `--all`
+struct Progress {
+    total: usize,
+    finished: usize,
+    /// Islands currently being processed
+    running: Vec<u8>,
+    failures: Vec<(u8, MsmError)>,
+    /// Redraw the status line in place. Otherwise only plain lines are printed, as the escape
+    /// sequences clearing the line must not end up in piped output or with `--color never`
+    live: bool,
+}
+
+impl Progress {
+    fn start(&mut self, island: u8) {
+        self.running.push(island);
+        self.draw();
+    }
+
+    fn finish(&mut self, island: u8, result: Result<()>) {
+        self.running.retain(|it| *it != island);
+        self.finished += 1;
+        if self.live {
+            cprint!("\r\x1b[2K");
+        }
+        let name = ISLAND_NAMES[island as usize];
+        match result {
+            Ok(()) => cprintln!("\x1b[32mCreated \x1b[1m{}\x1b[0m", name),
+            Err(e) => {
+                cprintln!("\x1b[31mFailed to create \x1b[1m{}\x1b[0m", name);
+                self.failures.push((island, e));
+            }
         }
-        if !msm.no_timeline {
-            display::display(&song, &world, &monster_names);
+        self.draw();
+    }
+
+    /// Prints a line of output of an island above the status line
+    fn print(&self, island: u8, line: &str) {
+        if self.live {
+            cprint!("\r\x1b[2K");
+        }
+        cprintln!("\x1b[1m{}:\x1b[22m {}", ISLAND_NAMES[island as usize], line);
+        self.draw();
+    }
+
+    fn draw(&self) {
+        if !self.live {
+            return;
+        }
+        cprint!(
+            "\r\x1b[2K\x1b[36m[{: >2}/{}]\x1b[0m {}",
+            self.finished,
+            self.total,
+            self.running
+                .iter()
+                .map(|it| ISLAND_NAMES[*it as usize])
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        std::io::stdout().flush().ok();
+    }
+}
+
+/// Creates the files of every island in parallel, using one worker per CPU core. Failed islands
+/// don't stop the other ones and are listed at the end
+fn create_all(context: &Context) -> Result<()> {
+    let islands: Vec<u8> = (0..ISLAND_NAMES.len() as u8)
+        .filter(|it| !ISLAND_NAMES[*it as usize].is_empty())
+        .collect();
+    let workers = thread::available_parallelism()
+        .map_or(1, |it| it.get())
+        .min(islands.len());
+    let next = AtomicUsize::new(0);
+    let progress = Arc::new(Mutex::new(Progress {
+        total: islands.len(),
+        finished: 0,
+        running: vec![],
+        failures: vec![],
+        live: std::io::stdout().is_terminal() && color::stdout_enabled(),
+    }));
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Some(island) = islands.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    progress.lock().unwrap().start(*island);
+                    let log = Log::new({
+                        let progress = Arc::clone(&progress);
+                        let island = *island;
+                        move |line| progress.lock().unwrap().print(island, line)
+                    });
+                    let result = create_island(context, *island, Some(log));
+                    progress.lock().unwrap().finish(*island, result);
+                }
+            });
         }
+    });
+
+    let mut progress = progress.lock().unwrap();
+    if progress.live {
+        cprint!("\r\x1b[2K");
+    }
+    progress.failures.sort_by_key(|it| it.0);
+    cprintln!(
+        "\x1b[1m{} of {} islands created\x1b[0m",
+        progress.total - progress.failures.len(),
+        progress.total
+    );
+    for (island, error) in progress.failures.iter() {
+        cprintln!(
+            "  \x1b[31m{}: {}\x1b[0m",
+            ISLAND_NAMES[*island as usize],
+            error.highlighted()
+        );
+    }
+    match progress.failures.len() {
+        0 => Ok(()),
+        failed => Err(MsmError::BatchFailed {
+            failed,
+            total: progress.total,
+        }),
     }
 }
@@ -0,0 +1,134 @@
+use crate::parse::SongData;
+use crate::{MsmError, Result};
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use serde_json::{Map, Value};
+
+const TICKS_PER_BEAT: u16 = 480;
+
+/// Writes the parsed song as a standard MIDI file to `{out_path}/{world}_{island}.mid`.
+///
+/// The first track holds the island name and all tempo changes. Every other track is named after
+/// the display name of its monster and plays the sound index of each part as its note number, e.g.
+/// sound `03` becomes note 3.
+pub fn write_midi(
+    data: &SongData,
+    world: &str,
+    out_path: &str,
+    monster_names: &Map<String, Value>,
+) -> Result<()> {
+    let island_name = data.island_name;
+    let to_ticks = |seconds: f64| (data.beats_at(seconds) * TICKS_PER_BEAT as f64).round() as u32;
+
+    let mut tracks = data.tracks.clone();
+    crate::sort_tracks(&mut tracks, monster_names);
+    let track_names: Vec<String> = tracks
+        .iter()
+        .map(|track| {
+            monster_names
+                .get(&track.monster_name())
+                .and_then(|it| it["name"].as_str())
+                .unwrap_or(track.name.as_str())
+                .to_owned()
+        })
+        .collect();
+
+    // Events with their absolute tick
+    let mut conductor: Vec<(u32, TrackEventKind)> = vec![(
+        0,
+        TrackEventKind::Meta(MetaMessage::TrackName(island_name.as_bytes())),
+    )];
+    for segment in data.tempo.iter() {
+        conductor.push((
+            (segment.start_beat * TICKS_PER_BEAT as f64).round() as u32,
+            TrackEventKind::Meta(MetaMessage::Tempo(u24::new(segment.microseconds_per_beat))),
+        ));
+    }
+    let mut smf_tracks = vec![to_track_events(conductor, to_ticks(data.duration))];
+
+    for (track, name) in tracks.iter().zip(track_names.iter()) {
+        let mut events: Vec<(u32, TrackEventKind)> = vec![(
+            0,
+            TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+        )];
+        for part in track.parts.iter() {
+            let key = match part.sound.as_ref().and_then(|it| it.parse::<u8>().ok()) {
+                Some(sound) if sound < 128 => u7::new(sound),
+                _ => continue,
+            };
+            let start = to_ticks(part.start);
+            let end = to_ticks(part.start + part.duration).max(start + 1);
+            events.push((
+                start,
+                TrackEventKind::Midi {
+                    channel: u4::new(0),
+                    message: MidiMessage::NoteOn {
+                        key,
+                        vel: u7::new(100),
+                    },
+                },
+            ));
+            events.push((
+                end,
+                TrackEventKind::Midi {
+                    channel: u4::new(0),
+                    message: MidiMessage::NoteOff {
+                        key,
+                        vel: u7::new(0),
+                    },
+                },
+            ));
+        }
+        smf_tracks.push(to_track_events(events, to_ticks(data.duration)));
+    }
+
+    if let Err(source) = std::fs::create_dir_all(out_path) {
+        return Err(MsmError::CreateDir {
+            path: out_path.to_owned(),
+            source,
+        });
+    }
+    let path = format!(
+        "{}/{}_{}.mid",
+        out_path,
+        world,
+        island_name.replace(' ', "-")
+    );
+    let smf = Smf {
+        header: Header::new(Format::Parallel, Timing::Metrical(u15::new(TICKS_PER_BEAT))),
+        tracks: smf_tracks,
+    };
+    smf.save(&path)
+        .map_err(|source| MsmError::MidiWrite { path, source })
+}
+
+/// Sorts events by their absolute tick, converts them to delta times and terminates the track at
+/// `end` at the earliest. Note offs are placed before note ons on the same tick
+fn to_track_events(mut events: Vec<(u32, TrackEventKind)>, end: u32) -> Vec<TrackEvent> {
+    events.sort_by_key(|(tick, kind)| {
+        let order = match kind {
+            TrackEventKind::Meta(_) => 0,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOff { .. },
+                ..
+            } => 1,
+            _ => 2,
+        };
+        (*tick, order)
+    });
+    let end = end.max(events.last().map_or(0, |it| it.0));
+    events.push((end, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+
+    let mut time = 0;
+    events
+        .into_iter()
+        .map(|(tick, kind)| {
+            let delta = tick - time;
+            time = tick;
+            TrackEvent {
+                delta: u28::new(delta),
+                kind,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,297 @@
+//! Processing of the floating-point mix bus before it is quantized to the output bit depth.
+//! Samples are normalized to the range -1 to 1 and stored as one vector per channel.
+
+use std::ops::Range;
+
+/// Highest sample level after normalization or limiting in dBFS
+const CEILING_DB: f32 = -1.0;
+/// Width of the soft knee of the limiter in dB
+const KNEE_DB: f32 = 4.0;
+/// Time for the limiter to recover from gain reduction in seconds
+const RELEASE_SECONDS: f32 = 0.05;
+
+/// How the mix bus is processed before quantizing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mix {
+    /// Hard clip every sample outside the output range, like the original i16 mixer
+    Clip,
+    /// Scale the whole song so its highest peak reaches -1 dBFS
+    Peak,
+    /// Scale the whole song to the given integrated loudness in LUFS. Peaks exceeding -1 dBFS
+    /// afterwards are limited. The loudness of a repeated song is measured over all repetitions,
+    /// see [`process_repeated`]
+    Loudness(f32),
+    /// Apply a soft-knee limiter with a ceiling of -1 dBFS
+    Limit,
+}
+
+/// Whether a sample is outside the range that can be represented in 16 bits
+pub fn is_clipped(sample: f32) -> bool {
+    !(-1.0..=32767.0 / 32768.0).contains(&sample)
+}
+
+/// Processes the mix bus in place
+pub fn process(channels: &mut [Vec<f32>], mix: Mix, sample_rate: u32) {
+    process_repeated(channels, mix, sample_rate, 0..0, 1);
+}
+
+/// Processes the mix bus of a song in which the samples in `repeated` play `count` times in place.
+/// Only the loudness measurement depends on the repetitions, the loudness blocks starting in
+/// `repeated` are counted `count` times
+pub fn process_repeated(
+    channels: &mut [Vec<f32>],
+    mix: Mix,
+    sample_rate: u32,
+    repeated: Range<usize>,
+    count: usize,
+) {
+    match mix {
+        Mix::Clip => {}
+        Mix::Peak => {
+            let peak = channels
+                .iter()
+                .flatten()
+                .fold(0.0f32, |peak, it| peak.max(it.abs()));
+            if peak > 0.0 {
+                apply_gain(channels, db_to_gain(CEILING_DB) / peak);
+            }
+        }
+        Mix::Loudness(target) => {
+            let weight = |start: usize| {
+                if repeated.contains(&start) {
+                    count as f64
+                } else {
+                    1.0
+                }
+            };
+            if let Some(loudness) = weighted_loudness(channels, sample_rate, weight) {
+                apply_gain(channels, db_to_gain(target - loudness));
+            }
+            limit(channels, sample_rate);
+        }
+        Mix::Limit => limit(channels, sample_rate),
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn apply_gain(channels: &mut [Vec<f32>], gain: f32) {
+    for sample in channels.iter_mut().flatten() {
+        *sample *= gain;
+    }
+}
+
+/// Soft-knee limiter with instant attack, linked across all channels
+fn limit(channels: &mut [Vec<f32>], sample_rate: u32) {
+    let release = (-1.0 / (RELEASE_SECONDS * sample_rate as f32)).exp();
+    let mut gain_db = 0.0f32;
+    for index in 0..channels.first().map_or(0, |it| it.len()) {
+        let level = channels
+            .iter()
+            .fold(0.0f32, |peak, it| peak.max(it[index].abs()));
+        let level_db = 20.0 * level.max(1e-9).log10();
+        let overshoot = level_db - CEILING_DB;
+        let target_db = if 2.0 * overshoot < -KNEE_DB {
+            0.0
+        } else if 2.0 * overshoot <= KNEE_DB {
+            -(overshoot + KNEE_DB / 2.0).powi(2) / (2.0 * KNEE_DB)
+        } else {
+            -overshoot
+        };
+        gain_db = if target_db < gain_db {
+            target_db
+        } else {
+            target_db + (gain_db - target_db) * release
+        };
+        let gain = db_to_gain(gain_db);
+        for channel in channels.iter_mut() {
+            channel[index] *= gain;
+        }
+    }
+}
+
+/// A biquad filter in direct form I
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Biquad {
+    fn filter(&self, samples: &[f64]) -> Vec<f64> {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        samples
+            .iter()
+            .map(|x| {
+                let x = *x;
+                let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2
+                    - self.a[0] * y1
+                    - self.a[1] * y2;
+                (x2, x1, y2, y1) = (x1, x, y1, y);
+                y
+            })
+            .collect()
+    }
+}
+
+/// The two stages of the K-weighting filter from ITU-R BS.1770 for the given sample rate
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    let k = (std::f64::consts::PI * 1681.974450955533 / fs).tan();
+    let q = 0.7071752369554196;
+    let vh = 10f64.powf(3.999843853973347 / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    let k = (std::f64::consts::PI * 38.13547087602444 / fs).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    [shelf, high_pass]
+}
+
+/// Gated integrated loudness in LUFS as specified by ITU-R BS.1770. `None` for silence
+pub fn integrated_loudness(channels: &[Vec<f32>], sample_rate: u32) -> Option<f32> {
+    weighted_loudness(channels, sample_rate, |_| 1.0)
+}
+
+/// [`integrated_loudness`] with each block counted as often as `weight` of its first sample says
+fn weighted_loudness(
+    channels: &[Vec<f32>],
+    sample_rate: u32,
+    weight: impl Fn(usize) -> f64,
+) -> Option<f32> {
+    let [shelf, high_pass] = k_weighting(sample_rate);
+    let weighted: Vec<Vec<f64>> = channels
+        .iter()
+        .map(|channel| {
+            let samples: Vec<f64> = channel.iter().map(|it| *it as f64).collect();
+            high_pass.filter(&shelf.filter(&samples))
+        })
+        .collect();
+
+    // Mean square of 400ms blocks with 75% overlap, summed over all channels
+    let block_len = (sample_rate as f64 * 0.4) as usize;
+    let step = block_len / 4;
+    let len = weighted.first().map_or(0, |it| it.len());
+    // Mean square and weight of each block
+    let mut blocks: Vec<(f64, f64)> = vec![];
+    let mut start = 0;
+    while start + block_len <= len {
+        let power = weighted
+            .iter()
+            .map(|it| {
+                it[start..start + block_len]
+                    .iter()
+                    .map(|x| x * x)
+                    .sum::<f64>()
+            })
+            .sum::<f64>()
+            / block_len as f64;
+        blocks.push((power, weight(start)));
+        start += step;
+    }
+
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let gated_mean = |threshold: f64| {
+        let (power, weight) = blocks
+            .iter()
+            .filter(|(power, _)| loudness(*power) > threshold)
+            .fold((0.0, 0.0), |(sum, weights), (power, weight)| {
+                (sum + power * weight, weights + weight)
+            });
+        if weight == 0.0 {
+            None
+        } else {
+            Some(power / weight)
+        }
+    };
+    let relative_threshold = loudness(gated_mean(-70.0)?) - 10.0;
+    Some(loudness(gated_mean(relative_threshold.max(-70.0))?) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn sine(amplitude: f32, frequency: f32, seconds: f32) -> Vec<f32> {
+        (0..(seconds * SAMPLE_RATE as f32) as usize)
+            .map(|index| {
+                let time = index as f32 / SAMPLE_RATE as f32;
+                amplitude * (time * frequency * std::f32::consts::TAU).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sine_loudness() {
+        // A full scale 1 kHz sine in one channel measures -3.01 LUFS, K-weighting barely changes it
+        let loudness = |channels: &[Vec<f32>]| integrated_loudness(channels, SAMPLE_RATE).unwrap();
+        assert!((loudness(&[sine(1.0, 1000.0, 2.0)]) + 3.01).abs() < 0.1);
+        assert!((loudness(&[sine(0.5, 1000.0, 2.0)]) + 9.03).abs() < 0.1);
+        let stereo = [sine(0.5, 1000.0, 2.0), sine(0.5, 1000.0, 2.0)];
+        assert!((loudness(&stereo) + 6.02).abs() < 0.1);
+        assert_eq!(integrated_loudness(&[vec![0.0; 96000]], SAMPLE_RATE), None);
+    }
+
+    #[test]
+    fn repeated_loudness() {
+        let (loud, quiet) = (sine(0.5, 1000.0, 2.0), sine(0.2, 1000.0, 2.0));
+        let once: Vec<f32> = loud.iter().chain(quiet.iter()).copied().collect();
+        let expanded: Vec<f32> = loud.repeat(5).into_iter().chain(quiet).collect();
+
+        let repeated = 0..loud.len();
+        let weighted = weighted_loudness(std::slice::from_ref(&once), SAMPLE_RATE, |start| {
+            if repeated.contains(&start) {
+                5.0
+            } else {
+                1.0
+            }
+        })
+        .unwrap();
+        let expected = integrated_loudness(&[expanded], SAMPLE_RATE).unwrap();
+        // Blocks crossing the end of the repeated part are only approximated
+        assert!(
+            (weighted - expected).abs() < 0.3,
+            "{} {}",
+            weighted,
+            expected
+        );
+        assert!(integrated_loudness(&[once], SAMPLE_RATE).unwrap() < expected - 1.0);
+    }
+
+    #[test]
+    fn limiter_ceiling() {
+        let ceiling = db_to_gain(CEILING_DB);
+        let mut channels = vec![sine(2.0, 440.0, 1.0), sine(0.5, 440.0, 1.0)];
+        limit(&mut channels, SAMPLE_RATE);
+        let peak = channels
+            .iter()
+            .flatten()
+            .fold(0.0f32, |peak, it| peak.max(it.abs()));
+        assert!(peak <= ceiling + 1e-6, "{}", peak);
+        assert!(peak > ceiling - 0.01, "{}", peak);
+
+        // Levels well below the knee are left alone
+        let quiet = vec![sine(0.1, 440.0, 1.0)];
+        let mut limited = quiet.clone();
+        limit(&mut limited, SAMPLE_RATE);
+        assert_eq!(limited, quiet);
+    }
+}
@@ -1,75 +1,218 @@
+use crate::assets::AssetSource;
+use crate::profile::IslandProfile;
+use crate::{MsmError, Result};
 use midly::{MetaMessage, MidiMessage, Timing, TrackEvent, TrackEventKind};
 use regex::Regex;
-use std::collections::HashMap;
+use serde::Serialize;
 use std::str;
 
-#[derive(Debug)]
+/// All island songs are in 4/4 time
+pub const BEATS_PER_BAR: usize = 4;
+
+#[derive(Debug, Serialize)]
 pub struct SongData {
     pub island: String,
+    #[serde(skip)]
+    pub island_name: &'static str,
     pub duration: f64,
-    pub bpm: u8,
+    /// All tempo changes of the song in chronological order. Never empty, the first segment always
+    /// starts at 0
+    pub tempo: Vec<TempoSegment>,
     pub tracks: Vec<Track>,
 }
-#[derive(Debug, Clone)]
+/// A section of a song with a constant tempo, lasting until the start of the next segment
+#[derive(Debug, Clone, Serialize)]
+pub struct TempoSegment {
+    /// Start of the segment in seconds
+    pub start: f64,
+    /// Start of the segment in beats
+    pub start_beat: f64,
+    pub microseconds_per_beat: u32,
+}
+
+impl TempoSegment {
+    pub fn bpm(&self) -> f64 {
+        60_000_000.0 / self.microseconds_per_beat as f64
+    }
+}
+
+impl SongData {
+    /// The tempo segment active at the given time in seconds
+    pub fn tempo_at(&self, seconds: f64) -> &TempoSegment {
+        self.tempo
+            .iter()
+            .rev()
+            .find(|it| it.start <= seconds)
+            .unwrap_or(&self.tempo[0])
+    }
+
+    /// The initial tempo of the song in beats per minute
+    pub fn bpm(&self) -> f64 {
+        self.tempo[0].bpm()
+    }
+
+    /// Converts a time in seconds to the number of beats since the start of the song
+    pub fn beats_at(&self, seconds: f64) -> f64 {
+        let segment = self.tempo_at(seconds);
+        segment.start_beat + (seconds - segment.start) * segment.bpm() / 60.0
+    }
+
+    /// Converts a number of beats since the start of the song to seconds
+    pub fn seconds_at(&self, beats: f64) -> f64 {
+        let segment = self
+            .tempo
+            .iter()
+            .rev()
+            .find(|it| it.start_beat <= beats)
+            .unwrap_or(&self.tempo[0]);
+        segment.start + (beats - segment.start_beat) * 60.0 / segment.bpm()
+    }
+
+    /// Converts a position in the song to seconds
+    pub fn position_seconds(&self, position: Position) -> f64 {
+        match position {
+            Position::Seconds(seconds) => seconds,
+            Position::Bar { bar, beat } => {
+                self.seconds_at(((bar - 1) as usize * BEATS_PER_BAR) as f64 + beat - 1.0)
+            }
+        }
+    }
+
+    /// The section of the song between `start` and `end` seconds, moved to start at 0. Parts that
+    /// started before `start` keep playing from where their sound was at that time
+    pub fn window(&self, start: f64, end: f64) -> SongData {
+        let start_beat = self.beats_at(start);
+        let mut tempo = vec![TempoSegment {
+            start: 0.0,
+            start_beat: 0.0,
+            microseconds_per_beat: self.tempo_at(start).microseconds_per_beat,
+        }];
+        tempo.extend(
+            self.tempo
+                .iter()
+                .filter(|it| it.start > start && it.start < end)
+                .map(|it| TempoSegment {
+                    start: it.start - start,
+                    start_beat: it.start_beat - start_beat,
+                    microseconds_per_beat: it.microseconds_per_beat,
+                }),
+        );
+
+        SongData {
+            island: self.island.clone(),
+            island_name: self.island_name,
+            duration: end - start,
+            tempo,
+            tracks: self
+                .tracks
+                .iter()
+                .map(|track| Track {
+                    parts: track
+                        .parts
+                        .iter()
+                        .filter(|part| part.start < end && part.start + part.duration > start)
+                        .map(|part| {
+                            let part_start = part.start.max(start);
+                            TrackPart {
+                                start: part_start - start,
+                                duration: (part.start + part.duration).min(end) - part_start,
+                                offset: part.offset + part_start - part.start,
+                                sound: part.sound.clone(),
+                            }
+                        })
+                        .collect(),
+                    ..track.clone()
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A position in a song, e.g. the start of `--start`/`--end`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Seconds(f64),
+    /// Bars and beats counted from 1, like in music notation. Beats may be fractional
+    Bar {
+        bar: u32,
+        beat: f64,
+    },
+}
+
+impl str::FromStr for Position {
+    type Err = MsmError;
+
+    /// Parses seconds like `12.5` or a bar and beat like `5:1`
+    fn from_str(raw: &str) -> Result<Position> {
+        let position = match raw.split_once(':') {
+            Some((bar, beat)) => bar
+                .parse::<u32>()
+                .ok()
+                .zip(beat.parse::<f64>().ok())
+                .filter(|(bar, beat)| *bar >= 1 && (1.0..BEATS_PER_BAR as f64 + 1.0).contains(beat))
+                .map(|(bar, beat)| Position::Bar { bar, beat }),
+            None => raw
+                .parse::<f64>()
+                .ok()
+                .filter(|it| it.is_finite() && *it >= 0.0)
+                .map(Position::Seconds),
+        };
+        position.ok_or_else(|| MsmError::InvalidPosition(raw.to_owned()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Track {
     pub name: String,
     pub dipster: Option<u8>,
     pub parts: Vec<TrackPart>,
 }
-#[derive(Debug, Clone)]
+impl Track {
+    /// The key of this track in `monster_names.json`
+    pub fn monster_name(&self) -> String {
+        match self.dipster {
+            Some(dipster) => format!("Q{:02}_Monster", dipster),
+            None => self.name.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TrackPart {
     pub start: f64,
     pub duration: f64,
+    /// Seconds into the sound at which the part begins. Only parts cut by
+    /// [`SongData::window`] don't start at the beginning of their sound
+    pub offset: f64,
     pub sound: Option<String>,
 }
 
 #[derive(Debug)]
 struct RawTrack<'a> {
     name: &'a str,
-    notes: Vec<(u32, u8, u32)>,
+    notes: Vec<(u64, u8, u64)>,
 }
 
+/// Parses `world{world}.mid` of the given assets
 pub fn parse(
-    filename: String,
-    world: &String,
-    exclude_list: &Vec<Regex>,
-    include_list: &Vec<Regex>,
-) -> SongData {
-    let replacements: HashMap<&str, &str> = HashMap::from([
-        ("RareBox_Monster", "O_Monster"),
-        ("sony_plant_Monster", "P02_Monster"),
-        ("sony_air_Monster", "P01_Monster"),
-        ("Accoustic Monster", "Z01_accoustic"),
-        ("Banjo Monster", "Z01_banjo"),
-        ("Bass Monster", "Z01_bass"),
-        ("Drums Monster", "Z01_drums"),
-        ("Electric1 Monster", "Z01_electricA"),
-        ("Electric2 Monster", "Z01_electricB"),
-        ("Mandolin Monster", "Z01_mandolin"),
-        ("Vocal Monster", "Z01_vocal"),
-        ("ABDE Monster", "ABDE_Monster"),
-        ("BE Monster", "BE_Monster"),
-        ("E Monster", "E_Monster"),
-        ("BD Monster", "BD_Monster"),
-        ("B Monster", "B_Monster"),
-        ("ACE Monster", "ACE_Monster"),
-        ("AD Monster", "AD_Monster"),
-    ]);
-
-    let file_bytes = std::fs::read(&filename).unwrap_or_else(|e| {
-        eprintln!(
-            "\x1b[31mFile \x1b[1m{}\x1b[22m could not be opened: {}\x1b[0m",
-            filename, e
-        );
-        std::process::exit(2);
-    });
-    let file_data = midly::Smf::parse(&file_bytes).unwrap_or_else(|e| {
-        eprintln!(
-            "\x1b[31mError while parsing \x1b[1m{}\x1b[22m as MIDI: {}\x1b[0m",
-            filename, e
-        );
-        std::process::exit(3);
-    });
+    assets: &dyn AssetSource,
+    world: &str,
+    profile: &IslandProfile,
+    exclude_list: &[Regex],
+    include_list: &[Regex],
+) -> Result<SongData> {
+    let island_name = crate::island_name(world)?;
+    let filename = assets.display_path(&format!("world{}.mid", world));
+    let file_bytes = assets.open_midi(world)?;
+    let file_data = match midly::Smf::parse(&file_bytes) {
+        Ok(data) => data,
+        Err(source) => {
+            return Err(MsmError::MidiParse {
+                path: filename,
+                source,
+            })
+        }
+    };
 
     let tracks: Vec<RawTrack> = file_data
         .tracks
@@ -77,25 +220,21 @@ pub fn parse(
         .map(|track| {
             let name = track
                 .iter()
-                .map(|it| {
-                    if let TrackEventKind::Meta(MetaMessage::TrackName(m)) = it.kind {
-                        str::from_utf8(m).unwrap()
-                    } else {
-                        ""
-                    }
+                .find_map(|it| match it.kind {
+                    TrackEventKind::Meta(MetaMessage::TrackName(m)) if !m.is_empty() => Some(m),
+                    _ => None,
                 })
-                .find(|it| !it.is_empty())
-                .unwrap_or_else(|| {
-                    eprintln!("\x1b[31mMalformed MIDI track: track name could not be found\x1b[0m");
-                    std::process::exit(4);
-                });
+                .ok_or(MsmError::MissingTrackName)?;
+            let name = str::from_utf8(name).map_err(|_| {
+                MsmError::InvalidTrackName(String::from_utf8_lossy(name).into_owned())
+            })?;
 
-            let mut start_time: u32 = 0;
-            let notes: Vec<(u32, u8, u32)> = track
+            let mut start_time: u64 = 0;
+            let notes: Vec<(u64, u8, u64)> = track
                 .iter()
                 .enumerate()
                 .map(|(index, event)| {
-                    let delta: u32 = event.delta.as_int();
+                    let delta = event.delta.as_int() as u64;
                     start_time += delta;
                     let sound: u8 = if let TrackEventKind::Midi {
                         channel: _,
@@ -106,16 +245,16 @@ pub fn parse(
                     } else {
                         255
                     };
-                    let duration: u32 = if let TrackEventKind::Midi {
+                    let duration: u64 = if let TrackEventKind::Midi {
                         channel: _,
                         message: MidiMessage::NoteOn { key: _, vel: _ },
                     } = event.kind
                     {
                         let mut index_delta: usize = 0;
                         let mut end_event: TrackEvent = *event;
-                        let mut duration_time: u32 = 0;
+                        let mut duration_time: u64 = 0;
                         loop {
-                            duration_time += end_event.delta.as_int();
+                            duration_time += end_event.delta.as_int() as u64;
                             if let TrackEventKind::Midi {
                                 channel: _,
                                 message: MidiMessage::NoteOff { key: _, vel: _ },
@@ -138,81 +277,56 @@ pub fn parse(
                 .filter(|it| it.1 != 255)
                 .collect();
 
-            RawTrack { name, notes }
+            Ok(RawTrack { name, notes })
         })
+        .collect::<Result<Vec<RawTrack>>>()?
+        .into_iter()
         .filter(|track| track.name.ends_with("Monster") || track.name == "Bass")
         .collect();
 
     let ticks_per_beat = if let Timing::Metrical(tpb) = file_data.header.timing {
         tpb.as_int()
     } else {
-        eprintln!(
-            "\x1b[31mTiming of MIDI file \x1b[1m{}\x1b[22m is not metrical",
-            filename
-        );
-        std::process::exit(5);
+        return Err(MsmError::NonMetricalTiming { path: filename });
     };
-    let microseconds_per_beat = file_data
-        .tracks
-        .iter()
-        .map(|track| {
-            track
-                .iter()
-                .map(|event| {
-                    if let TrackEventKind::Meta(MetaMessage::Tempo(t)) = event.kind {
-                        t.as_int()
-                    } else {
-                        20_000_000
-                    }
-                })
-                .find(|it| it != &20_000_000)
-        })
-        .find(|it| it.is_some())
-        .unwrap_or_else(|| {
-            eprintln!("\x1b[31mMalformed MIDI file: tempo not specified\x1b[0m");
-            std::process::exit(6);
-        })
-        .unwrap();
-    let beats_per_second: f64 = 1000000f64 / microseconds_per_beat as f64;
-    let ticks_per_second: f64 = beats_per_second * ticks_per_beat as f64;
+    let tempo_map = TempoMap::new(&file_data.tracks, ticks_per_beat)?;
 
     let song_duration_ticks = file_data
         .tracks
         .iter()
         .map(|track| {
-            let mut start_time: u32 = 0;
-            for event in track.iter() {
-                start_time += event.delta.as_int();
-            }
-            start_time
+            track
+                .iter()
+                .map(|event| event.delta.as_int() as u64)
+                .sum::<u64>()
         })
         .max()
-        .unwrap();
-    let song_duration = song_duration_ticks as f64 / ticks_per_second;
+        .unwrap_or(0);
+    let song_duration = tempo_map.seconds(song_duration_ticks);
 
     let mut result: SongData = SongData {
-        island: world.clone(),
+        island: world.to_owned(),
+        island_name,
         duration: song_duration,
-        bpm: (beats_per_second * 60.0) as u8,
+        tempo: tempo_map
+            .segments
+            .iter()
+            .map(|(_, segment)| segment.clone())
+            .collect(),
         tracks: vec![],
     };
+    let dipster_regex = Regex::new(r"^Q\d\d_Monster$").unwrap();
     for track in tracks {
-        if world == "09" && track.name == "Bass" {
+        if profile.skips(track.name) {
             continue;
         }
-        let is_dipster = Regex::new(r"^Q\d\d_Monster$").unwrap().is_match(track.name);
+        let is_dipster = dipster_regex.is_match(track.name);
 
         let mut track_data: Track = Track {
             name: if is_dipster {
                 "Q_Monster"
-            } else if world == "05" && track.name == "Bass" {
-                "bass"
-            } else if world == "18" && track.name == "Bass_Monster" {
-                "Bass"
-            } else if replacements.contains_key(track.name) {
-                replacements.get(track.name).unwrap()
             } else {
-                track.name
+                profile.rename(track.name)
             }
             .to_string(),
             dipster: if is_dipster {
@@ -224,11 +338,7 @@ pub fn parse(
         };
 
         // Exclude/include
-        let monster_name = if track_data.dipster.is_none() {
-            track_data.name.clone()
-        } else {
-            format!("Q{:02}_Monster", track_data.dipster.unwrap())
-        };
+        let monster_name = track_data.monster_name();
         if (!include_list.is_empty() && !include_list.iter().any(|it| it.is_match(&monster_name)))
             || (!exclude_list.is_empty()
                 && exclude_list.iter().any(|it| it.is_match(&monster_name)))
@@ -238,29 +348,89 @@ pub fn parse(
 
         for note in track.notes {
             track_data.parts.push(TrackPart {
-                start: note.0 as f64 / ticks_per_second,
-                duration: note.2 as f64 / ticks_per_second,
-                sound: if note.1 == 73 && track.name == "Box_Monster" {
-                    None
-                } else if note.1 == 102 && world == "03" && track.name == "Q05_Monster" {
-                    Some("24".to_string())
-                } else if world == "18" && track.name == "EW_Monster" {
-                    Some(format!("{:02}", note.1 - 72))
-                } else if is_dipster {
-                    if world.parse::<u8>().unwrap() >= 13 {
-                        Some((note.1 - 48).to_string())
-                    } else {
-                        Some(note.1.to_string())
-                    }
-                } else if note.1 == 113 && world == "03" && track.name == "AD_Monster" {
-                    Some("03".to_string())
-                } else {
-                    Some(format!("{:02}", note.1 - 71))
-                },
+                start: tempo_map.seconds(note.0),
+                duration: tempo_map.seconds(note.0 + note.2) - tempo_map.seconds(note.0),
+                offset: 0.0,
+                sound: profile.sound(track.name, note.1, is_dipster),
             })
         }
         result.tracks.push(track_data);
     }
 
-    result
+    Ok(result)
+}
+
+/// Maps absolute MIDI ticks to seconds, honoring every tempo change
+struct TempoMap {
+    ticks_per_beat: u16,
+    /// Tempo segments paired with their start tick
+    segments: Vec<(u64, TempoSegment)>,
+}
+
+impl TempoMap {
+    /// Collects the tempo events of all tracks. The first tempo is assumed to apply from the very
+    /// start of the song
+    fn new(tracks: &[Vec<TrackEvent>], ticks_per_beat: u16) -> Result<TempoMap> {
+        let mut events: Vec<(u64, u32)> = vec![];
+        for track in tracks {
+            let mut time: u64 = 0;
+            for event in track {
+                time += event.delta.as_int() as u64;
+                if let TrackEventKind::Meta(MetaMessage::Tempo(t)) = event.kind {
+                    events.push((time, t.as_int()));
+                }
+            }
+        }
+        // Stable sort, so with multiple tempo events on the same tick the one from the later
+        // track wins
+        events.sort_by_key(|it| it.0);
+        events.dedup_by(|next, prev| {
+            if next.0 == prev.0 {
+                prev.1 = next.1;
+                true
+            } else {
+                false
+            }
+        });
+        if events.is_empty() {
+            return Err(MsmError::MissingTempo);
+        }
+        events[0].0 = 0;
+
+        let mut segments: Vec<(u64, TempoSegment)> = vec![];
+        for (tick, microseconds_per_beat) in events {
+            let segment = match segments.last() {
+                Some((prev_tick, prev)) => {
+                    let beats = (tick - prev_tick) as f64 / ticks_per_beat as f64;
+                    TempoSegment {
+                        start: prev.start + beats * prev.microseconds_per_beat as f64 / 1_000_000.0,
+                        start_beat: prev.start_beat + beats,
+                        microseconds_per_beat,
+                    }
+                }
+                None => TempoSegment {
+                    start: 0.0,
+                    start_beat: 0.0,
+                    microseconds_per_beat,
+                },
+            };
+            segments.push((tick, segment));
+        }
+        Ok(TempoMap {
+            ticks_per_beat,
+            segments,
+        })
+    }
+
+    /// Converts an absolute tick to seconds
+    fn seconds(&self, tick: u64) -> f64 {
+        let (start_tick, segment) = self
+            .segments
+            .iter()
+            .rev()
+            .find(|(start_tick, _)| *start_tick <= tick)
+            .unwrap_or(&self.segments[0]);
+        let beats = (tick - start_tick) as f64 / self.ticks_per_beat as f64;
+        segment.start + beats * segment.microseconds_per_beat as f64 / 1_000_000.0
+    }
 }
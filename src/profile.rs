@@ -0,0 +1,278 @@
+use crate::{MsmError, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Rules for translating MIDI tracks and notes to sound file names, see `res/island_profile.json`
+/// for a description of the format
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// Each profile merged into this one, starting with the lowest precedence
+    layers: Vec<Layer>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Layer {
+    global: Rules,
+    islands: HashMap<String, Rules>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Rules {
+    renames: HashMap<String, String>,
+    skip: Vec<String>,
+    /// Tracks skipped by rules with lower precedence that should be kept
+    keep: Vec<String>,
+    notes: Vec<NoteRule>,
+    note_offset: Option<u8>,
+    dipster_offset: Option<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NoteRule {
+    /// The raw MIDI track name this rule applies to
+    pub track: String,
+    /// The note this rule applies to. Applies to all notes if `None`
+    pub note: Option<u8>,
+    pub sound: NoteSound,
+}
+
+#[derive(Debug, Clone)]
+pub enum NoteSound {
+    /// Always use this sound, `None` meaning no sound at all
+    Fixed(Option<String>),
+    /// Subtract this from the note number to get the sound number
+    Offset(u8),
+}
+
+/// The effective rules for a single island
+#[derive(Debug, Clone)]
+pub struct IslandProfile {
+    pub renames: HashMap<String, String>,
+    pub skip: Vec<String>,
+    pub notes: Vec<NoteRule>,
+    pub note_offset: u8,
+    pub dipster_offset: u8,
+}
+
+impl Profile {
+    /// The profile embedded in the binary
+    pub fn embedded() -> Profile {
+        let raw_profile: Value = serde_json::from_reader(json_comments::StripComments::new(
+            &include_bytes!("res/island_profile.json")[..],
+        ))
+        .unwrap();
+        Profile::from_value(&raw_profile).unwrap()
+    }
+
+    /// Reads a profile from a JSON file. Comments are allowed
+    pub fn from_file(path: String) -> Result<Profile> {
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(source) => return Err(MsmError::ProfileOpen { path, source }),
+        };
+        let parsed = serde_json::from_reader(json_comments::StripComments::new(file))
+            .map_err(|e| e.to_string())
+            .and_then(|raw_profile: Value| Profile::from_value(&raw_profile));
+        parsed.map_err(|message| MsmError::ProfileParse { path, message })
+    }
+
+    fn from_value(value: &Value) -> Result<Profile, String> {
+        let object = value.as_object().ok_or("profile is not an object")?;
+        let mut islands = HashMap::new();
+        if let Some(raw_islands) = object.get("islands") {
+            for (island, rules) in raw_islands
+                .as_object()
+                .ok_or("`islands` is not an object")?
+            {
+                let rules =
+                    Rules::from_value(rules).map_err(|e| format!("island {}: {}", island, e))?;
+                islands.insert(island.clone(), rules);
+            }
+        }
+        Ok(Profile {
+            layers: vec![Layer {
+                global: Rules::from_value(value)?,
+                islands,
+            }],
+        })
+    }
+
+    /// Merges another profile into this one. Rules of `other` take precedence, including its
+    /// global rules over the island rules of this profile
+    pub fn extend(&mut self, other: Profile) {
+        self.layers.extend(other.layers);
+    }
+
+    /// The effective rules for the given island, e.g. `"03"`
+    pub fn island(&self, world: &str) -> IslandProfile {
+        let mut rules = Rules::default();
+        for layer in &self.layers {
+            rules.extend(layer.global.clone());
+            if let Some(island_rules) = layer.islands.get(world) {
+                rules.extend(island_rules.clone());
+            }
+        }
+        IslandProfile {
+            renames: rules.renames,
+            skip: rules.skip,
+            notes: rules.notes,
+            note_offset: rules.note_offset.unwrap_or(71),
+            dipster_offset: rules.dipster_offset.unwrap_or(0),
+        }
+    }
+}
+
+impl Rules {
+    fn from_value(value: &Value) -> Result<Rules, String> {
+        let object = value.as_object().ok_or("rules are not an object")?;
+        let mut rules = Rules::default();
+        if let Some(renames) = object.get("renames") {
+            for (from, to) in renames.as_object().ok_or("`renames` is not an object")? {
+                let to = to
+                    .as_str()
+                    .ok_or(format!("rename of {} is not a string", from))?;
+                rules.renames.insert(from.clone(), to.to_owned());
+            }
+        }
+        if let Some(skip) = object.get("skip") {
+            for name in skip.as_array().ok_or("`skip` is not an array")? {
+                let name = name.as_str().ok_or("`skip` contains a non-string value")?;
+                rules.skip.push(name.to_owned());
+            }
+        }
+        if let Some(keep) = object.get("keep") {
+            for name in keep.as_array().ok_or("`keep` is not an array")? {
+                let name = name.as_str().ok_or("`keep` contains a non-string value")?;
+                rules.keep.push(name.to_owned());
+            }
+        }
+        if let Some(notes) = object.get("notes") {
+            for (index, rule) in notes
+                .as_array()
+                .ok_or("`notes` is not an array")?
+                .iter()
+                .enumerate()
+            {
+                let rule = rule
+                    .as_object()
+                    .ok_or(format!("note rule {} is not an object", index))
+                    .and_then(NoteRule::from_object)
+                    .map_err(|e| format!("note rule {}: {}", index, e))?;
+                rules.notes.push(rule);
+            }
+        }
+        rules.note_offset = parse_u8(object, "note_offset")?;
+        rules.dipster_offset = parse_u8(object, "dipster_offset")?;
+        Ok(rules)
+    }
+
+    fn extend(&mut self, other: Rules) {
+        self.renames.extend(other.renames);
+        self.skip.retain(|it| !other.keep.contains(it));
+        self.skip.extend(other.skip);
+        self.notes.splice(0..0, other.notes);
+        self.note_offset = other.note_offset.or(self.note_offset);
+        self.dipster_offset = other.dipster_offset.or(self.dipster_offset);
+    }
+}
+
+impl NoteRule {
+    fn from_object(object: &Map<String, Value>) -> Result<NoteRule, String> {
+        let track = object
+            .get("track")
+            .and_then(|it| it.as_str())
+            .ok_or("`track` is missing or not a string")?
+            .to_owned();
+        let sound = match (object.get("sound"), parse_u8(object, "offset")?) {
+            (Some(Value::Null), None) => NoteSound::Fixed(None),
+            (Some(Value::String(sound)), None) => NoteSound::Fixed(Some(sound.clone())),
+            (None, Some(offset)) => NoteSound::Offset(offset),
+            (Some(_), None) => return Err("`sound` is neither a string nor null".to_owned()),
+            _ => return Err("exactly one of `sound` and `offset` has to be specified".to_owned()),
+        };
+        Ok(NoteRule {
+            track,
+            note: parse_u8(object, "note")?,
+            sound,
+        })
+    }
+
+    fn matches(&self, track: &str, note: u8) -> bool {
+        self.track == track && self.note.is_none_or(|it| it == note)
+    }
+}
+
+impl IslandProfile {
+    /// Whether the raw MIDI track should be ignored
+    pub fn skips(&self, track: &str) -> bool {
+        self.skip.iter().any(|it| it == track)
+    }
+
+    /// The monster name used for the sound files of a raw MIDI track
+    pub fn rename<'a>(&'a self, track: &'a str) -> &'a str {
+        self.renames.get(track).map_or(track, |it| it.as_str())
+    }
+
+    /// The sound played by a note of a raw MIDI track
+    pub fn sound(&self, track: &str, note: u8, is_dipster: bool) -> Option<String> {
+        match self.notes.iter().find(|it| it.matches(track, note)) {
+            Some(NoteRule {
+                sound: NoteSound::Fixed(sound),
+                ..
+            }) => sound.clone(),
+            Some(NoteRule {
+                sound: NoteSound::Offset(offset),
+                ..
+            }) => Some(format!("{:02}", note.saturating_sub(*offset))),
+            None if is_dipster => Some(note.saturating_sub(self.dipster_offset).to_string()),
+            None => Some(format!("{:02}", note.saturating_sub(self.note_offset))),
+        }
+    }
+}
+
+fn parse_u8(object: &Map<String, Value>, key: &str) -> Result<Option<u8>, String> {
+    match object.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .and_then(|it| u8::try_from(it).ok())
+            .map(Some)
+            .ok_or(format!("`{}` is not a number between 0 and 255", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn profile(value: Value) -> Profile {
+        Profile::from_value(&value).unwrap()
+    }
+
+    #[test]
+    fn precedence() {
+        let mut rules = profile(json!({
+            "dipster_offset": 1,
+            "islands": { "13": { "dipster_offset": 48, "note_offset": 60 } }
+        }));
+        rules.extend(profile(json!({
+            "dipster_offset": 2,
+            "islands": { "13": { "note_offset": 50 } }
+        })));
+        let island = rules.island("13");
+        assert_eq!(island.dipster_offset, 2);
+        assert_eq!(island.note_offset, 50);
+        assert_eq!(rules.island("01").dipster_offset, 2);
+        assert_eq!(rules.island("01").note_offset, 71);
+    }
+
+    #[test]
+    fn keep_skipped_track() {
+        let mut rules = profile(json!({ "islands": { "09": { "skip": ["Bass", "Drums"] } } }));
+        rules.extend(profile(json!({ "keep": ["Bass"] })));
+        let island = rules.island("09");
+        assert!(!island.skips("Bass"));
+        assert!(island.skips("Drums"));
+    }
+}
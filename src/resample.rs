@@ -0,0 +1,203 @@
+//! Band-limited resampling using a Kaiser-windowed sinc filter, with linear interpolation as a
+//! fast fallback.
+
+/// Number of precomputed kernel values between two zero crossings of the sinc function
+const OVERSAMPLING: usize = 256;
+/// Cutoff frequency relative to the lower of both Nyquist frequencies. Leaves room for the
+/// transition band of the filter
+const ROLLOFF: f64 = 0.95;
+
+/// Resampling quality, from fastest to best
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    /// Linear interpolation. Fast, but not band-limited
+    Linear,
+    /// Windowed sinc with 8 zero crossings on each side
+    Low,
+    /// Windowed sinc with 16 zero crossings on each side
+    Medium,
+    /// Windowed sinc with 32 zero crossings on each side
+    High,
+}
+
+/// One half of a windowed sinc kernel, sampled `OVERSAMPLING` times per zero crossing
+struct Kernel {
+    zero_crossings: usize,
+    table: Vec<f64>,
+}
+
+impl Kernel {
+    fn new(zero_crossings: usize, beta: f64) -> Kernel {
+        let table = (0..=zero_crossings * OVERSAMPLING + 1)
+            .map(|index| {
+                let x = index as f64 / OVERSAMPLING as f64;
+                if x >= zero_crossings as f64 {
+                    return 0.0;
+                }
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+                };
+                let ratio = x / zero_crossings as f64;
+                sinc * bessel_i0(beta * (1.0 - ratio * ratio).sqrt()) / bessel_i0(beta)
+            })
+            .collect();
+        Kernel {
+            zero_crossings,
+            table,
+        }
+    }
+
+    /// The kernel value at `x` zero crossings from the center
+    fn value(&self, x: f64) -> f64 {
+        let position = x.abs() * OVERSAMPLING as f64;
+        let index = position as usize;
+        if index + 1 >= self.table.len() {
+            return 0.0;
+        }
+        let fraction = position - index as f64;
+        self.table[index] + (self.table[index + 1] - self.table[index]) * fraction
+    }
+}
+
+/// Modified Bessel function of the first kind of order zero, used for the Kaiser window
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)).powi(2);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+/// Resamples `samples` to exactly `new_len` samples. Changing the length by a factor changes the
+/// sample rate by the same factor, e.g. doubling the length converts 22050 Hz to 44100 Hz
+pub fn resample(samples: &[f32], new_len: usize, quality: Quality) -> Vec<f32> {
+    let old_len = samples.len();
+    if old_len == 0 || new_len == 0 {
+        return vec![0.0; new_len];
+    }
+    if old_len == new_len {
+        return samples.to_vec();
+    }
+    // Input samples per output sample
+    let step = old_len as f64 / new_len as f64;
+
+    let kernel = match quality {
+        Quality::Linear => {
+            return (0..new_len)
+                .map(|index| {
+                    let position = index as f64 * step;
+                    let left = position as usize;
+                    let right = (left + 1).min(old_len - 1);
+                    let fraction = (position - left as f64) as f32;
+                    samples[left] + (samples[right] - samples[left]) * fraction
+                })
+                .collect();
+        }
+        Quality::Low => Kernel::new(8, 6.0),
+        Quality::Medium => Kernel::new(16, 8.0),
+        Quality::High => Kernel::new(32, 10.0),
+    };
+
+    // When downsampling, the cutoff is lowered below the new Nyquist frequency to avoid aliasing
+    let cutoff = (1.0 / step).min(1.0) * ROLLOFF;
+    let half_width = kernel.zero_crossings as f64 / cutoff;
+    (0..new_len)
+        .map(|index| {
+            let position = index as f64 * step;
+            let first = (position - half_width).ceil().max(0.0) as usize;
+            let last = ((position + half_width).floor() as usize).min(old_len - 1);
+            let mut sum = 0.0;
+            for (input_index, sample) in samples.iter().enumerate().take(last + 1).skip(first) {
+                sum += *sample as f64 * kernel.value((position - input_index as f64) * cutoff);
+            }
+            (sum * cutoff) as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUALITIES: [Quality; 4] = [
+        Quality::Linear,
+        Quality::Low,
+        Quality::Medium,
+        Quality::High,
+    ];
+
+    fn sine(frequency: f64, sample_rate: f64, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|index| {
+                (2.0 * std::f64::consts::PI * frequency * index as f64 / sample_rate).sin() as f32
+            })
+            .collect()
+    }
+
+    /// The peak of `samples`, leaving out the edges where the kernel runs past the input
+    fn peak(samples: &[f32]) -> f32 {
+        let edge = samples.len() / 8;
+        samples[edge..samples.len() - edge]
+            .iter()
+            .fold(0.0, |peak, it| it.abs().max(peak))
+    }
+
+    #[test]
+    fn length() {
+        for quality in QUALITIES {
+            assert_eq!(resample(&[0.5; 1000], 2000, quality).len(), 2000);
+            assert_eq!(resample(&[0.5; 1000], 441, quality).len(), 441);
+            assert_eq!(resample(&[], 10, quality), [0.0; 10]);
+        }
+    }
+
+    #[test]
+    fn dc_level() {
+        for quality in QUALITIES {
+            for new_len in [2000, 1470, 500] {
+                let out = resample(&[0.5; 1000], new_len, quality);
+                let edge = new_len / 8;
+                for sample in &out[edge..new_len - edge] {
+                    assert!(
+                        (sample - 0.5).abs() < 0.01,
+                        "{:?} {}: {}",
+                        quality,
+                        new_len,
+                        sample
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sine_level() {
+        // 1 kHz from 22050 Hz to 44100 Hz
+        let samples = sine(1000.0, 22050.0, 4410);
+        for quality in QUALITIES {
+            let out = resample(&samples, 8820, quality);
+            assert!(
+                (peak(&out) - 1.0).abs() < 0.02,
+                "{:?}: {}",
+                quality,
+                peak(&out)
+            );
+        }
+    }
+
+    #[test]
+    fn downsampling_filters_aliases() {
+        // 15 kHz is above the Nyquist frequency of 22050 Hz
+        let samples = sine(15000.0, 44100.0, 8820);
+        for quality in [Quality::Low, Quality::Medium, Quality::High] {
+            let out = resample(&samples, 4410, quality);
+            assert!(peak(&out) < 0.05, "{:?}: {}", quality, peak(&out));
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Time-stretching using WSOLA (waveform similarity overlap-add). Changes the speed of audio
+//! without changing its pitch.
+
+/// Length of the overlapping frames in seconds
+const FRAME_SECONDS: f64 = 0.04;
+
+/// Changes the speed of every channel by `tempo` while keeping the pitch, i.e. a tempo of 2 halves
+/// the length. All channels are stretched identically, so stereo images stay intact
+pub fn time_stretch(channels: &[Vec<f32>], tempo: f32, sample_rate: u32) -> Vec<Vec<f32>> {
+    let len = channels.first().map_or(0, |it| it.len());
+    let out_len = (len as f64 / tempo as f64).round() as usize;
+    let frame_len = ((FRAME_SECONDS * sample_rate as f64) as usize / 2 * 2).max(2);
+    let synthesis_hop = frame_len / 2;
+    let tolerance = frame_len as isize / 8;
+    let window: Vec<f32> = (0..frame_len)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / frame_len as f32).cos())
+        .collect();
+
+    // The frames are aligned using a mono mix of all channels
+    let mono: Vec<f32> = (0..len)
+        .map(|index| channels.iter().map(|it| it[index]).sum())
+        .collect();
+    let sample = |index: isize| {
+        if index < 0 || index as usize >= len {
+            0.0
+        } else {
+            mono[index as usize]
+        }
+    };
+    let similarity = |a: isize, b: isize, step: usize| {
+        (0..frame_len as isize)
+            .step_by(step)
+            .map(|i| sample(a + i) * sample(b + i))
+            .sum::<f32>()
+    };
+
+    let mut out = vec![vec![0.0; out_len + frame_len]; channels.len()];
+    let mut prev_pos: Option<isize> = None;
+    // Start half a frame early, so the start isn't faded in by the window
+    let mut out_pos = -(synthesis_hop as isize);
+    while out_pos < out_len as isize {
+        let nominal = (out_pos as f64 * tempo as f64).round() as isize;
+        let pos = match prev_pos {
+            None => nominal,
+            Some(prev_pos) => {
+                // The frame most similar to the natural continuation of the previous frame,
+                // searching coarsely first and refining around the best match
+                let target = prev_pos + synthesis_hop as isize;
+                let best = |range: std::ops::RangeInclusive<isize>, step: usize, corr_step| {
+                    range
+                        .step_by(step)
+                        .map(|it| (it, similarity(target, it, corr_step)))
+                        .fold(
+                            (nominal, f32::MIN),
+                            |best, it| {
+                                if it.1 > best.1 {
+                                    it
+                                } else {
+                                    best
+                                }
+                            },
+                        )
+                        .0
+                };
+                let coarse = best(nominal - tolerance..=nominal + tolerance, 4, 4);
+                best(coarse - 3..=coarse + 3, 1, 2)
+            }
+        };
+
+        for (channel, out) in channels.iter().zip(out.iter_mut()) {
+            for (i, weight) in window.iter().enumerate() {
+                let index = pos + i as isize;
+                let out_index = out_pos + i as isize;
+                if index >= 0 && (index as usize) < len && out_index >= 0 {
+                    out[out_index as usize] += channel[index as usize] * weight;
+                }
+            }
+        }
+        prev_pos = Some(pos);
+        out_pos += synthesis_hop as isize;
+    }
+
+    for channel in out.iter_mut() {
+        channel.truncate(out_len);
+    }
+    out
+}
@@ -0,0 +1,180 @@
+use crate::color;
+use crate::display::{bpm_label, duration_label};
+use crate::parse::{SongData, BEATS_PER_BAR};
+use crate::{MsmError, Result};
+use serde_json::{Map, Value};
+
+const PX_PER_BEAT: f64 = 6.0;
+const LABEL_WIDTH: f64 = 130.0;
+const HEADER_HEIGHT: f64 = 40.0;
+const RULER_HEIGHT: f64 = 24.0;
+const ROW_HEIGHT: f64 = 20.0;
+const BACKGROUND: &str = "#1e1e1e";
+const FOREGROUND: &str = "#e0e0e0";
+
+/// File formats for exporting the song timeline as a graphic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimelineFormat {
+    Svg,
+    Html,
+}
+
+impl TimelineFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TimelineFormat::Svg => "svg",
+            TimelineFormat::Html => "html",
+        }
+    }
+}
+
+/// Renders the timeline of a song as an SVG graphic, with one row per monster, a bar and beat
+/// ruler and a header with the island name, tempo and duration
+pub fn svg(data: &SongData, monster_names: &Map<String, Value>) -> String {
+    let island_name = data.island_name;
+    let total_bars = (data.beats_at(data.duration) / BEATS_PER_BAR as f64).ceil() as usize;
+    let total_beats = total_bars * BEATS_PER_BAR;
+    let mut tracks = data.tracks.clone();
+    crate::sort_tracks(&mut tracks, monster_names);
+
+    let rows_top = HEADER_HEIGHT + RULER_HEIGHT;
+    let width = (LABEL_WIDTH + total_beats as f64 * PX_PER_BEAT + 10.0).max(400.0);
+    let height = rows_top + tracks.len() as f64 * ROW_HEIGHT + 10.0;
+    let beat_x = |beat: f64| LABEL_WIDTH + beat * PX_PER_BEAT;
+
+    let mut out = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">
+<rect width="{w}" height="{h}" fill="{BACKGROUND}"/>
+<text x="10" y="26" fill="{FOREGROUND}"><tspan font-weight="bold" font-size="16">{}</tspan> - {} {}m</text>
+"#,
+        escape(island_name),
+        bpm_label(data),
+        duration_label(data.duration),
+        w = width,
+        h = height,
+    );
+
+    // Ruler and grid
+    for beat in 0..=total_beats {
+        let x = beat_x(beat as f64);
+        if beat % BEATS_PER_BAR == 0 {
+            out += &format!(
+                r##"<line x1="{x}" y1="{}" x2="{x}" y2="{}" stroke="#808080" stroke-width="1"/>
+"##,
+                HEADER_HEIGHT + 4.0,
+                height - 10.0,
+            );
+            if beat < total_beats {
+                out += &format!(
+                    r#"<text x="{}" y="{}" fill="{FOREGROUND}" font-size="9">{}</text>
+"#,
+                    x + 2.0,
+                    HEADER_HEIGHT + 12.0,
+                    beat / BEATS_PER_BAR + 1,
+                );
+            }
+        } else {
+            out += &format!(
+                r##"<line x1="{x}" y1="{}" x2="{x}" y2="{}" stroke="#505050" stroke-width="1"/>
+"##,
+                rows_top - 6.0,
+                rows_top,
+            );
+        }
+    }
+
+    for (row, track) in tracks.iter().enumerate() {
+        let monster_data = monster_names.get(&track.monster_name());
+        let name = monster_data
+            .and_then(|it| it["name"].as_str())
+            .unwrap_or("Unknown");
+        let fill = color::to_hex(color::MonsterColor::from_monster(monster_data).rgb());
+        let y = rows_top + row as f64 * ROW_HEIGHT;
+
+        out += &format!(
+            r#"<text x="{}" y="{}" fill="{FOREGROUND}" text-anchor="end">{}</text>
+"#,
+            LABEL_WIDTH - 8.0,
+            y + ROW_HEIGHT * 0.7,
+            escape(name),
+        );
+        for part in track.parts.iter() {
+            let sound = match &part.sound {
+                Some(sound) => sound,
+                None => continue,
+            };
+            let start = data.beats_at(part.start);
+            let end = data.beats_at(part.start + part.duration);
+            out += &format!(
+                r#"<rect x="{:.1}" y="{}" width="{:.1}" height="{}" rx="2" fill="{fill}"><title>{} - sound {} at {}</title></rect>
+"#,
+                beat_x(start),
+                y + 2.0,
+                ((end - start) * PX_PER_BEAT).max(1.0),
+                ROW_HEIGHT - 4.0,
+                escape(name),
+                escape(sound),
+                duration_label(part.start),
+            );
+        }
+    }
+
+    out += "</svg>\n";
+    out
+}
+
+/// Renders the timeline of a song as a standalone HTML page containing the [`svg`] graphic
+pub fn html(data: &SongData, monster_names: &Map<String, Value>) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{}</title>
+<style>body {{ background: {BACKGROUND}; margin: 0; }}</style>
+</head>
+<body>
+{}</body>
+</html>
+"#,
+        escape(data.island_name),
+        svg(data, monster_names),
+    )
+}
+
+/// Writes the timeline of a song to `{out_path}/{world}_{island}.{svg,html}`
+pub fn write_timeline(
+    data: &SongData,
+    world: &str,
+    out_path: &str,
+    monster_names: &Map<String, Value>,
+    format: TimelineFormat,
+) -> Result<()> {
+    let content = match format {
+        TimelineFormat::Svg => svg(data, monster_names),
+        TimelineFormat::Html => html(data, monster_names),
+    };
+
+    if let Err(source) = std::fs::create_dir_all(out_path) {
+        return Err(MsmError::CreateDir {
+            path: out_path.to_owned(),
+            source,
+        });
+    }
+    let path = format!(
+        "{}/{}_{}.{}",
+        out_path,
+        world,
+        data.island_name.replace(' ', "-"),
+        format.extension()
+    );
+    std::fs::write(&path, content).map_err(|source| MsmError::TimelineWrite { path, source })
+}
+
+/// Escapes text for use in XML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,897 @@
+//! A small Ogg Vorbis encoder.
+//!
+//! Uses a single block size, a floor 1 curve following the spectral envelope of each block and
+//! residue type 1 with cascaded vector codebooks. All codebooks get Huffman codes built from a
+//! first analysis pass over the audio.
+
+use crate::write::Repeated;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::collections::BinaryHeap;
+use std::f64::consts::PI;
+use std::io::{self, Write};
+
+const BLOCK_SIZE: usize = 2048;
+/// Number of spectral coefficients per block, which is also the hop between blocks
+const HALF: usize = BLOCK_SIZE / 2;
+const PARTITION_SIZE: usize = 32;
+const FLOOR_MULTIPLIER: usize = 2;
+const FLOOR_RANGE: i32 = 128;
+/// Interior floor positions in spectral coefficients, denser at low frequencies
+const FLOOR_POSTS: [usize; 34] = [
+    1, 2, 3, 4, 5, 6, 8, 10, 12, 14, 16, 20, 24, 28, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160,
+    192, 224, 256, 320, 384, 448, 512, 640, 768, 896,
+];
+/// Quantization step of the residue relative to the amplitude of each frequency band in dB.
+/// Higher values give better quality and larger files
+const NOISE_OFFSET: f64 = 26.0;
+/// Lowest quantization step, about -100 dBFS
+const MIN_FLOOR: f64 = 1e-5;
+const SERIAL: u32 = 0x6d736d;
+
+/// Highest residue magnitude and codebooks of each pass for every residue classification
+const RESIDUE_CLASSES: [(i32, &[usize]); 8] = [
+    (0, &[]),
+    (1, &[2]),
+    (2, &[3]),
+    (4, &[4]),
+    (8, &[5]),
+    (16, &[6]),
+    (263, &[7, 5]),
+    (8168, &[8, 7, 5]),
+];
+
+/// Writes a song as an Ogg Vorbis stream, clipping samples to -1..1. `tags` are stored as Vorbis
+/// comments
+pub fn write_vorbis<W: Write>(
+    writer: W,
+    song: &Repeated,
+    sample_rate: u32,
+    tags: &[(&str, String)],
+) -> io::Result<()> {
+    let total = song.len;
+    // Block `n` is centered on sample `n * HALF`, the last block has to reach past the end
+    let block_count = total.div_ceil(HALF) + 1;
+    let mut encoder = Encoder::new(song);
+
+    // Analyse the first repetition to build the Huffman codes
+    for block in 0..block_count.min(song.period().div_ceil(HALF) + 2) {
+        for symbol in encoder.block_symbols(block) {
+            if let Symbol::Entry(book, entry) = symbol {
+                encoder.books[book].counts[entry] += 1;
+            }
+        }
+    }
+    for book in encoder.books.iter_mut() {
+        book.build_codes();
+    }
+
+    let mut packets = PacketWriter::new(writer);
+    packets.write_packet(
+        identification_header(song.channel_count(), sample_rate).into_boxed_slice(),
+        SERIAL,
+        PacketWriteEndInfo::EndPage,
+        0,
+    )?;
+    packets.write_packet(
+        comment_header(tags).into_boxed_slice(),
+        SERIAL,
+        PacketWriteEndInfo::NormalPacket,
+        0,
+    )?;
+    packets.write_packet(
+        encoder.setup_header().into_boxed_slice(),
+        SERIAL,
+        PacketWriteEndInfo::EndPage,
+        0,
+    )?;
+
+    for block in 0..block_count {
+        let mut out = BitWriter::default();
+        for symbol in encoder.block_symbols(block) {
+            match symbol {
+                Symbol::Bits(value, bits) => out.write(value, bits),
+                Symbol::Entry(book, entry) => encoder.books[book].write(&mut out, entry),
+            }
+        }
+        // Decoders only start counting samples at the end of a page, which the last page needs
+        // to trim the padding of the final block
+        let end_info = if block + 1 == block_count {
+            PacketWriteEndInfo::EndStream
+        } else if block == 0 {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        packets.write_packet(
+            out.finish().into_boxed_slice(),
+            SERIAL,
+            end_info,
+            (block * HALF).min(total) as u64,
+        )?;
+    }
+    packets.inner_mut().flush()
+}
+
+fn identification_header(channels: usize, sample_rate: u32) -> Vec<u8> {
+    let mut out = vec![1];
+    out.extend(b"vorbis");
+    out.extend(0u32.to_le_bytes());
+    out.push(channels as u8);
+    out.extend(sample_rate.to_le_bytes());
+    // Maximum, nominal and minimum bitrate are unset
+    out.extend([0; 12]);
+    let exponent = BLOCK_SIZE.trailing_zeros() as u8;
+    out.push(exponent << 4 | exponent);
+    out.push(1);
+    out
+}
+
+fn comment_header(tags: &[(&str, String)]) -> Vec<u8> {
+    let vendor = concat!("msm ", env!("CARGO_PKG_VERSION"));
+    let mut out = vec![3];
+    out.extend(b"vorbis");
+    out.extend((vendor.len() as u32).to_le_bytes());
+    out.extend(vendor.as_bytes());
+    out.extend((tags.len() as u32).to_le_bytes());
+    for (key, value) in tags {
+        let comment = format!("{}={}", key, value);
+        out.extend((comment.len() as u32).to_le_bytes());
+        out.extend(comment.as_bytes());
+    }
+    out.push(1);
+    out
+}
+
+/// Part of an audio packet, either raw bits or an entry of a codebook
+enum Symbol {
+    Bits(u32, u32),
+    Entry(usize, usize),
+}
+
+struct Encoder<'a> {
+    song: &'a Repeated,
+    books: Vec<Codebook>,
+    window: Vec<f64>,
+    mdct: Mdct,
+    /// Floor positions in the order they are coded, starting with 0 and `HALF`
+    floor_x: Vec<usize>,
+}
+
+impl<'a> Encoder<'a> {
+    fn new(song: &'a Repeated) -> Self {
+        // Positions are coded coarse to fine, so every one is predicted from close neighbors
+        fn subdivide(posts: &[usize], out: &mut Vec<usize>) {
+            if posts.is_empty() {
+                return;
+            }
+            let middle = posts.len() / 2;
+            out.push(posts[middle]);
+            subdivide(&posts[..middle], out);
+            subdivide(&posts[middle + 1..], out);
+        }
+        let mut floor_x = vec![0, HALF];
+        subdivide(&FLOOR_POSTS, &mut floor_x);
+
+        let books = vec![
+            // Floor values
+            Codebook::scalar(FLOOR_RANGE as usize),
+            // Residue classifications of two partitions
+            Codebook {
+                dimensions: 2,
+                ..Codebook::scalar(RESIDUE_CLASSES.len().pow(2))
+            },
+            Codebook::lattice(4, -1, 1, 3),
+            Codebook::lattice(2, -2, 1, 5),
+            Codebook::lattice(2, -4, 1, 9),
+            Codebook::lattice(2, -8, 1, 17),
+            Codebook::lattice(2, -16, 1, 33),
+            Codebook::lattice(2, -255, 17, 31),
+            Codebook::lattice(2, -7905, 527, 31),
+        ];
+
+        Encoder {
+            song,
+            books,
+            window: (0..BLOCK_SIZE)
+                .map(|n| {
+                    let x = ((n as f64 + 0.5) / BLOCK_SIZE as f64 * PI).sin();
+                    (PI / 2.0 * x * x).sin()
+                })
+                .collect(),
+            mdct: Mdct::new(BLOCK_SIZE),
+            floor_x,
+        }
+    }
+
+    fn setup_header(&self) -> Vec<u8> {
+        let mut out = BitWriter::default();
+        out.write(5, 8);
+        for byte in b"vorbis" {
+            out.write(*byte as u32, 8);
+        }
+
+        out.write(self.books.len() as u32 - 1, 8);
+        for book in self.books.iter() {
+            book.write_header(&mut out);
+        }
+
+        // Unused time domain transforms
+        out.write(0, 6);
+        out.write(0, 16);
+
+        // One floor of type 1, with two values per partition
+        out.write(0, 6);
+        out.write(1, 16);
+        let partitions = (self.floor_x.len() - 2) / 2;
+        out.write(partitions as u32, 5);
+        for _ in 0..partitions {
+            out.write(0, 4);
+        }
+        out.write(1, 3);
+        out.write(0, 2);
+        out.write(1, 8);
+        out.write(FLOOR_MULTIPLIER as u32 - 1, 2);
+        let range_bits = HALF.trailing_zeros();
+        out.write(range_bits, 4);
+        for x in self.floor_x[2..].iter() {
+            out.write(*x as u32, range_bits);
+        }
+
+        // One residue of type 1
+        out.write(0, 6);
+        out.write(1, 16);
+        out.write(0, 24);
+        out.write(HALF as u32, 24);
+        out.write(PARTITION_SIZE as u32 - 1, 24);
+        out.write(RESIDUE_CLASSES.len() as u32 - 1, 6);
+        out.write(1, 8);
+        for (_, books) in RESIDUE_CLASSES.iter() {
+            out.write((1 << books.len()) - 1, 3);
+            out.write(0, 1);
+        }
+        for (_, books) in RESIDUE_CLASSES.iter() {
+            for book in books.iter() {
+                out.write(*book as u32, 8);
+            }
+        }
+
+        // One mapping without channel coupling
+        out.write(0, 6);
+        out.write(0, 16);
+        out.write(0, 4);
+        out.write(0, 24);
+
+        // One mode
+        out.write(0, 6);
+        out.write(0, 1);
+        out.write(0, 32);
+        out.write(0, 8);
+
+        out.write(1, 1);
+        out.finish()
+    }
+
+    /// Spectrum of each channel for the block centered on sample `block * HALF`
+    fn spectra(&self, block: usize) -> Vec<Vec<f64>> {
+        let start = block as isize * HALF as isize - HALF as isize;
+        (0..self.song.channel_count())
+            .map(|channel| {
+                let input: Vec<f64> = (0..BLOCK_SIZE)
+                    .map(|n| {
+                        let index = start + n as isize;
+                        if index < 0 || index as usize >= self.song.len {
+                            0.0
+                        } else {
+                            let sample = self.song.sample(channel, index as usize);
+                            sample.clamp(-1.0, 1.0) as f64 * self.window[n]
+                        }
+                    })
+                    .collect();
+                self.mdct.forward(&input)
+            })
+            .collect()
+    }
+
+    /// Codes one audio packet
+    fn block_symbols(&self, block: usize) -> Vec<Symbol> {
+        // Packet type and mode number, which takes no bits with only one mode
+        let mut symbols = vec![Symbol::Bits(0, 1)];
+        let mut residues: Vec<Option<Vec<i32>>> = vec![];
+
+        for spectrum in self.spectra(block) {
+            if spectrum.iter().all(|it| it.abs() < MIN_FLOOR / 2.0) {
+                symbols.push(Symbol::Bits(0, 1));
+                residues.push(None);
+                continue;
+            }
+            symbols.push(Symbol::Bits(1, 1));
+            let (values, floor) = self.floor(&spectrum);
+            symbols.push(Symbol::Bits(values[0], 7));
+            symbols.push(Symbol::Bits(values[1], 7));
+            symbols.extend(values[2..].iter().map(|it| Symbol::Entry(0, *it as usize)));
+            residues.push(Some(
+                spectrum
+                    .iter()
+                    .zip(floor)
+                    .map(|(coefficient, floor)| {
+                        ((coefficient / floor).round() as i32).clamp(-8168, 8168)
+                    })
+                    .collect(),
+            ));
+        }
+
+        // Classify the partitions of every coded channel by their highest magnitude
+        let residues: Vec<(Vec<i32>, Vec<usize>)> = residues
+            .into_iter()
+            .flatten()
+            .map(|residue| {
+                let classes = residue
+                    .chunks(PARTITION_SIZE)
+                    .map(|partition| {
+                        let max = partition.iter().map(|it| it.abs()).max().unwrap_or(0);
+                        RESIDUE_CLASSES.iter().position(|it| max <= it.0).unwrap()
+                    })
+                    .collect();
+                (residue, classes)
+            })
+            .collect();
+
+        let partitions = HALF / PARTITION_SIZE;
+        let class_count = RESIDUE_CLASSES.len();
+        for pass in 0..3 {
+            for group in (0..partitions).step_by(2) {
+                if pass == 0 {
+                    for (_, classes) in residues.iter() {
+                        let second = classes.get(group + 1).copied().unwrap_or(0);
+                        symbols.push(Symbol::Entry(1, classes[group] * class_count + second));
+                    }
+                }
+                for partition in group..(group + 2).min(partitions) {
+                    for (residue, classes) in residues.iter() {
+                        let book = match RESIDUE_CLASSES[classes[partition]].1.get(pass) {
+                            Some(book) => *book,
+                            None => continue,
+                        };
+                        let values =
+                            &residue[partition * PARTITION_SIZE..(partition + 1) * PARTITION_SIZE];
+                        let passes = RESIDUE_CLASSES[classes[partition]].1.len();
+                        let values: Vec<i32> = values
+                            .iter()
+                            .map(|value| cascade_part(*value, passes, pass))
+                            .collect();
+                        let book_data = &self.books[book];
+                        for vector in values.chunks(book_data.dimensions) {
+                            symbols.push(Symbol::Entry(book, book_data.entry(vector)));
+                        }
+                    }
+                }
+            }
+        }
+        symbols
+    }
+
+    /// Chooses the floor values for a spectrum. Returns the coded values and the resulting floor
+    /// curve, which is the quantization step of every coefficient
+    fn floor(&self, spectrum: &[f64]) -> (Vec<u32>, Vec<f64>) {
+        let mut sorted = self.floor_x.clone();
+        sorted.sort_unstable();
+
+        // Target values from the amplitude of the band around each position
+        let targets: Vec<i32> = self
+            .floor_x
+            .iter()
+            .map(|x| {
+                let index = sorted.iter().position(|it| it == x).unwrap();
+                let low = if index == 0 {
+                    0
+                } else {
+                    (sorted[index - 1] + x) / 2
+                };
+                let high = if index + 1 == sorted.len() {
+                    HALF
+                } else {
+                    (x + sorted[index + 1]).div_ceil(2)
+                };
+                let (low, high) = (low.min(HALF - 1), high.clamp(low.min(HALF - 1) + 1, HALF));
+                let energy =
+                    spectrum[low..high].iter().map(|it| it * it).sum::<f64>() / (high - low) as f64;
+                let step = (energy.sqrt() * 10f64.powf(-NOISE_OFFSET / 20.0)).max(MIN_FLOOR);
+                let index = (step / FLOOR_DB_MIN).ln() / FLOOR_DB_STEP.ln();
+                ((index / FLOOR_MULTIPLIER as f64).round() as i32).clamp(0, FLOOR_RANGE - 1)
+            })
+            .collect();
+
+        // Code the values as differences to their prediction, like the decoder will read them
+        let mut values = vec![targets[0] as u32, targets[1] as u32];
+        let mut final_y = vec![targets[0], targets[1]];
+        let mut used = vec![true, true];
+        for (i, target) in targets.iter().enumerate().skip(2) {
+            let (low, high) = self.neighbors(i);
+            let predicted = render_point(
+                self.floor_x[low],
+                final_y[low],
+                self.floor_x[high],
+                final_y[high],
+                self.floor_x[i],
+            );
+            let high_room = FLOOR_RANGE - predicted;
+            let low_room = predicted;
+            let room = 2 * high_room.min(low_room);
+            let difference = target - predicted;
+            let value = if difference == 0 {
+                0
+            } else if difference > 0 {
+                if 2 * difference < room {
+                    2 * difference
+                } else {
+                    difference + low_room
+                }
+            } else if -2 * difference - 1 < room {
+                -2 * difference - 1
+            } else {
+                -difference + high_room - 1
+            };
+            if value != 0 {
+                used[low] = true;
+                used[high] = true;
+                used.push(true);
+                final_y.push(*target);
+            } else {
+                used.push(false);
+                final_y.push(predicted);
+            }
+            values.push(value as u32);
+        }
+
+        // Render the curve exactly like the decoder
+        let mut points: Vec<(usize, i32)> = self
+            .floor_x
+            .iter()
+            .zip(final_y.iter())
+            .zip(used.iter())
+            .filter(|(_, used)| **used)
+            .map(|((x, y), _)| (*x, *y * FLOOR_MULTIPLIER as i32))
+            .collect();
+        points.sort_unstable_by_key(|it| it.0);
+        let mut curve = vec![0; HALF];
+        for pair in points.windows(2) {
+            render_line(pair[0].0, pair[0].1, pair[1].0, pair[1].1, &mut curve);
+        }
+        let floor = curve
+            .iter()
+            .map(|it| FLOOR_DB_MIN * FLOOR_DB_STEP.powi(*it))
+            .collect();
+        (values, floor)
+    }
+
+    /// Indices of the closest previously coded positions below and above position `i`
+    fn neighbors(&self, i: usize) -> (usize, usize) {
+        let x = self.floor_x[i];
+        let previous = &self.floor_x[..i];
+        let low = (0..i)
+            .filter(|it| previous[*it] < x)
+            .max_by_key(|it| previous[*it])
+            .unwrap();
+        let high = (0..i)
+            .filter(|it| previous[*it] > x)
+            .min_by_key(|it| previous[*it])
+            .unwrap();
+        (low, high)
+    }
+}
+
+/// Smallest value of the floor 1 decibel table
+const FLOOR_DB_MIN: f64 = 1.0649863e-07;
+/// Ratio between two consecutive values of the floor 1 decibel table
+const FLOOR_DB_STEP: f64 = 1.0649863;
+
+/// The part of `value` coded in pass `pass` of a residue class with `passes` passes. The passes
+/// use steps of 527, 17 and 1, finishing with the finest
+fn cascade_part(value: i32, passes: usize, pass: usize) -> i32 {
+    let steps = &[527, 17, 1][3 - passes..];
+    let mut rest = value;
+    for (index, step) in steps.iter().enumerate() {
+        let part = (rest as f64 / *step as f64).round() as i32;
+        if index == pass {
+            return part * step;
+        }
+        rest -= part * step;
+    }
+    0
+}
+
+fn render_point(x0: usize, y0: i32, x1: usize, y1: i32, x: usize) -> i32 {
+    let dy = y1 - y0;
+    let adx = (x1 - x0) as i32;
+    let offset = dy.abs() * (x - x0) as i32 / adx;
+    if dy < 0 {
+        y0 - offset
+    } else {
+        y0 + offset
+    }
+}
+
+fn render_line(x0: usize, y0: i32, x1: usize, y1: i32, curve: &mut [i32]) {
+    let dy = y1 - y0;
+    let adx = (x1 - x0) as i32;
+    let base = dy / adx;
+    let sy = if dy < 0 { base - 1 } else { base + 1 };
+    let ady = dy.abs() - base.abs() * adx;
+    let mut y = y0;
+    let mut error = 0;
+    curve[x0] = y;
+    for value in curve.iter_mut().take(x1).skip(x0 + 1) {
+        error += ady;
+        if error >= adx {
+            error -= adx;
+            y += sy;
+        } else {
+            y += base;
+        }
+        *value = y;
+    }
+}
+
+struct Codebook {
+    dimensions: usize,
+    /// Minimum, step and number of values per dimension of vector codebooks
+    lattice: Option<(i32, i32, usize)>,
+    counts: Vec<u64>,
+    lengths: Vec<u32>,
+    codes: Vec<u32>,
+}
+
+impl Codebook {
+    fn scalar(entries: usize) -> Self {
+        Codebook {
+            dimensions: 1,
+            lattice: None,
+            counts: vec![0; entries],
+            lengths: vec![],
+            codes: vec![],
+        }
+    }
+
+    fn lattice(dimensions: usize, minimum: i32, step: i32, values: usize) -> Self {
+        Codebook {
+            dimensions,
+            lattice: Some((minimum, step, values)),
+            ..Codebook::scalar(values.pow(dimensions as u32))
+        }
+    }
+
+    /// The entry of a vector codebook holding `vector`
+    fn entry(&self, vector: &[i32]) -> usize {
+        let (minimum, step, values) = self.lattice.unwrap();
+        vector.iter().rev().fold(0, |entry, it| {
+            entry * values + ((it - minimum) / step) as usize
+        })
+    }
+
+    /// Builds length-limited Huffman codes from the counts. Every entry gets a code, so values
+    /// that didn't appear during the analysis can still be coded
+    fn build_codes(&mut self) {
+        let mut counts: Vec<u64> = self.counts.iter().map(|it| it + 1).collect();
+        loop {
+            self.lengths = huffman_lengths(&counts);
+            if self.lengths.iter().all(|it| *it <= 24) {
+                break;
+            }
+            counts = counts.iter().map(|it| it / 2 + 1).collect();
+        }
+
+        // Assign codes in entry order like the decoder does
+        let mut marker = [0u32; 33];
+        self.codes = self
+            .lengths
+            .iter()
+            .map(|length| {
+                let length = *length as usize;
+                let code = marker[length];
+                for j in (1..=length).rev() {
+                    if marker[j] & 1 != 0 {
+                        marker[j] = if j == 1 {
+                            marker[1] + 1
+                        } else {
+                            marker[j - 1] << 1
+                        };
+                        break;
+                    }
+                    marker[j] += 1;
+                }
+                let mut entry = code;
+                for j in length + 1..33 {
+                    if marker[j] >> 1 == entry {
+                        entry = marker[j];
+                        marker[j] = marker[j - 1] << 1;
+                    } else {
+                        break;
+                    }
+                }
+                code
+            })
+            .collect();
+    }
+
+    fn write(&self, out: &mut BitWriter, entry: usize) {
+        let length = self.lengths[entry];
+        // Codes are read starting with their most significant bit
+        out.write(self.codes[entry].reverse_bits() >> (32 - length), length);
+    }
+
+    fn write_header(&self, out: &mut BitWriter) {
+        out.write(0x564342, 24);
+        out.write(self.dimensions as u32, 16);
+        out.write(self.lengths.len() as u32, 24);
+        // Not ordered and not sparse
+        out.write(0, 2);
+        for length in self.lengths.iter() {
+            out.write(length - 1, 5);
+        }
+        match self.lattice {
+            None => out.write(0, 4),
+            Some((minimum, step, values)) => {
+                out.write(1, 4);
+                out.write(vorbis_float(minimum), 32);
+                out.write(vorbis_float(step), 32);
+                let value_bits = 32 - (values as u32 - 1).leading_zeros();
+                out.write(value_bits - 1, 4);
+                out.write(0, 1);
+                for value in 0..values {
+                    out.write(value as u32, value_bits);
+                }
+            }
+        }
+    }
+}
+
+/// Packs an integer into the float format used in codebook headers
+fn vorbis_float(value: i32) -> u32 {
+    let sign = if value < 0 { 1 << 31 } else { 0 };
+    sign | 788 << 21 | value.unsigned_abs()
+}
+
+/// Code lengths of a Huffman code for the given symbol counts
+fn huffman_lengths(counts: &[u64]) -> Vec<u32> {
+    // Nodes are (count, id), children of internal nodes are stored in `parents`
+    let mut heap: BinaryHeap<std::cmp::Reverse<(u64, usize)>> = counts
+        .iter()
+        .enumerate()
+        .map(|(index, count)| std::cmp::Reverse((*count, index)))
+        .collect();
+    let mut parents = vec![usize::MAX; counts.len()];
+    while heap.len() > 1 {
+        let std::cmp::Reverse((count_a, a)) = heap.pop().unwrap();
+        let std::cmp::Reverse((count_b, b)) = heap.pop().unwrap();
+        let id = parents.len();
+        parents.push(usize::MAX);
+        parents[a] = id;
+        parents[b] = id;
+        heap.push(std::cmp::Reverse((count_a + count_b, id)));
+    }
+    (0..counts.len())
+        .map(|mut node| {
+            let mut length = 0;
+            while parents[node] != usize::MAX {
+                node = parents[node];
+                length += 1;
+            }
+            length.max(1)
+        })
+        .collect()
+}
+
+/// MDCT computed with a complex FFT of a quarter of the block size
+struct Mdct {
+    size: usize,
+    pre_twiddles: Vec<(f64, f64)>,
+    post_twiddles: Vec<(f64, f64)>,
+    fft_twiddles: Vec<(f64, f64)>,
+}
+
+impl Mdct {
+    fn new(size: usize) -> Self {
+        let half = size / 2;
+        let quarter = size / 4;
+        Mdct {
+            size,
+            pre_twiddles: (0..quarter)
+                .map(|n| {
+                    let angle = -PI * (4 * n + 1) as f64 / (4 * half) as f64;
+                    (angle.cos(), angle.sin())
+                })
+                .collect(),
+            post_twiddles: (0..quarter)
+                .map(|k| {
+                    let angle = -PI * k as f64 / half as f64;
+                    (angle.cos(), angle.sin())
+                })
+                .collect(),
+            fft_twiddles: (0..quarter / 2)
+                .map(|k| {
+                    let angle = -2.0 * PI * k as f64 / quarter as f64;
+                    (angle.cos(), angle.sin())
+                })
+                .collect(),
+        }
+    }
+
+    /// Transforms `size` windowed samples into `size / 2` coefficients, scaled for the inverse
+    /// transform of the decoder
+    fn forward(&self, input: &[f64]) -> Vec<f64> {
+        let half = self.size / 2;
+        let quarter = self.size / 4;
+
+        // Fold into a DCT-IV of the sequence (-c_r - d, a - b_r) of the quarters a, b, c, d
+        let folded: Vec<f64> = (0..half)
+            .map(|n| {
+                if n < quarter {
+                    -input[3 * quarter - 1 - n] - input[3 * quarter + n]
+                } else {
+                    input[n - quarter] - input[3 * quarter - 1 - n]
+                }
+            })
+            .collect();
+
+        // DCT-IV using a complex FFT of half its length
+        let mut data: Vec<(f64, f64)> = (0..quarter)
+            .map(|n| {
+                let (re, im) = (folded[2 * n], folded[half - 1 - 2 * n]);
+                let (c, s) = self.pre_twiddles[n];
+                (re * c - im * s, re * s + im * c)
+            })
+            .collect();
+        self.fft(&mut data);
+
+        let scale = 4.0 / self.size as f64;
+        let mut out = vec![0.0; half];
+        for (k, (re, im)) in data.into_iter().enumerate() {
+            let (c, s) = self.post_twiddles[k];
+            let (re, im) = (re * c - im * s, re * s + im * c);
+            out[2 * k] = re * scale;
+            out[half - 1 - 2 * k] = -im * scale;
+        }
+        out
+    }
+
+    /// In-place radix-2 FFT
+    fn fft(&self, data: &mut [(f64, f64)]) {
+        let len = data.len();
+        let mut j = 0;
+        for i in 1..len {
+            let mut bit = len >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+        let mut size = 2;
+        while size <= len {
+            let stride = len / size;
+            for start in (0..len).step_by(size) {
+                for k in 0..size / 2 {
+                    let (c, s) = self.fft_twiddles[k * stride];
+                    let (re, im) = data[start + k + size / 2];
+                    let odd = (re * c - im * s, re * s + im * c);
+                    let even = data[start + k];
+                    data[start + k] = (even.0 + odd.0, even.1 + odd.1);
+                    data[start + k + size / 2] = (even.0 - odd.0, even.1 - odd.1);
+                }
+            }
+            size *= 2;
+        }
+    }
+}
+
+/// Writes bits least significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= ((value as u64) & ((1 << bits) - 1)) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write::test_signal as signal;
+    use lewton::inside_ogg::OggStreamReader;
+    use std::io::Cursor;
+
+    /// Lowest accepted signal-to-noise ratio of the decoded song in dB
+    const MIN_SNR: f64 = 30.0;
+
+    /// Encodes `channels`, decodes them with lewton and returns the decoded channels after
+    /// checking the header fields and tags
+    fn round_trip(channels: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        let song = Repeated::from_channels(channels, 44100);
+        let tags = vec![
+            ("TITLE", "Plant Island".to_owned()),
+            ("REPEATS", "1".to_owned()),
+        ];
+        let mut bytes = vec![];
+        write_vorbis(&mut bytes, &song, 44100, &tags).unwrap();
+
+        let mut reader = OggStreamReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.ident_hdr.audio_sample_rate, 44100);
+        assert_eq!(reader.ident_hdr.audio_channels as usize, channels.len());
+        assert_eq!(
+            reader.comment_hdr.comment_list,
+            tags.iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect::<Vec<_>>()
+        );
+
+        let mut decoded = vec![vec![]; channels.len()];
+        while let Some(packet) = reader.read_dec_packet().unwrap() {
+            for (channel, samples) in decoded.iter_mut().zip(packet) {
+                channel.extend(samples.into_iter().map(|it| it as f32 / 32768.0));
+            }
+        }
+        for channel in decoded.iter() {
+            assert_eq!(channel.len(), channels[0].len());
+        }
+        decoded
+    }
+
+    /// Signal-to-noise ratio of `decoded` compared to `original` in dB
+    fn snr(original: &[Vec<f32>], decoded: &[Vec<f32>]) -> f64 {
+        let (mut signal, mut noise) = (0.0, 0.0);
+        for (original, decoded) in original.iter().zip(decoded) {
+            for (a, b) in original.iter().zip(decoded) {
+                signal += (*a as f64).powi(2);
+                noise += (*a as f64 - *b as f64).powi(2);
+            }
+        }
+        10.0 * (signal / noise).log10()
+    }
+
+    #[test]
+    fn mono() {
+        let channels = signal(1, 20000);
+        assert!(snr(&channels, &round_trip(&channels)) > MIN_SNR);
+    }
+
+    #[test]
+    fn stereo() {
+        let channels = signal(2, 20000);
+        assert!(snr(&channels, &round_trip(&channels)) > MIN_SNR);
+    }
+
+    #[test]
+    fn odd_length() {
+        let channels = signal(2, 3 * HALF + 17);
+        assert!(snr(&channels, &round_trip(&channels)) > MIN_SNR);
+        let channels = signal(1, 101);
+        round_trip(&channels);
+    }
+
+    #[test]
+    fn silence() {
+        let decoded = round_trip(&[vec![0.0; 5000], vec![0.0; 5000]]);
+        assert!(decoded.iter().flatten().all(|it| it.abs() < 1e-3));
+    }
+}
@@ -1,178 +1,977 @@
-use crate::parse::SongData;
-use crate::ISLAND_NAMES;
+use crate::assets::{self, AssetSource, Sample, SampleFormat};
+use crate::cache::{SampleCache, Segment};
+use crate::mix::{self, Mix};
+use crate::parse::{SongData, Track, TrackPart};
+use crate::resample::{self, Quality};
+use crate::{cprintln, flac, stretch, vorbis, MsmError, Result};
 use lewton::inside_ogg::OggStreamReader;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufWriter, Cursor, Seek, SeekFrom, Write};
+use std::sync::Arc;
 
-fn resize_vec(vec: Vec<i16>, size: usize) -> Vec<i16> {
-    let mut out: Vec<Option<i16>> = vec![None; size];
-    let old_size = vec.len();
-    for (index, elem) in vec.iter().enumerate() {
-        out[index * size / old_size] = Some(*elem);
+/// Options for rendering a song
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// Logs extra output while processing
+    pub verbose: bool,
+    /// Where the extra output of `verbose` goes
+    pub log: Log,
+    /// Tempo of the song. Just like in-game this will also change the pitch, unless
+    /// `time_stretch` is set
+    pub tempo: f32,
+    /// Change the tempo without changing the pitch
+    pub time_stretch: bool,
+    /// Shift the pitch by this many semitones without changing the duration
+    pub pitch: f32,
+    /// How many times the song should be repeated. Sounds still playing at the end of a repetition
+    /// continue into the next one
+    pub repeats: u8,
+    /// Let the sounds still playing at the end of the last repetition ring out instead of cutting
+    /// them off
+    pub tail: bool,
+    /// Length of a fade-out at the end of the song in seconds, 0 for none
+    pub fade: f32,
+    /// Render a stereo file instead of a mono one
+    pub stereo: bool,
+    /// Pan positions from -1 (left) to 1 (right) for each monster, keyed by the monster names used
+    /// in `monster_names.json`. Monsters without a pan position are centered. Only used when
+    /// rendering in stereo
+    pub pans: HashMap<String, f32>,
+    /// How the mix is processed before it is written
+    pub mix: Mix,
+    /// Quality used for resampling segments to `sample_rate` and for changing the tempo or pitch
+    pub resample_quality: Quality,
+    /// Sample rate of the output in Hz
+    pub sample_rate: u32,
+    /// Sample format of the output. FLAC files use 24 bits instead of floating point samples, Ogg
+    /// Vorbis files ignore it
+    pub bit_depth: BitDepth,
+    /// File format of the output
+    pub format: AudioFormat,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            verbose: false,
+            log: Log::default(),
+            tempo: 1.0,
+            time_stretch: false,
+            pitch: 0.0,
+            repeats: 1,
+            tail: false,
+            fade: 0.0,
+            stereo: false,
+            pans: HashMap::new(),
+            mix: Mix::Clip,
+            resample_quality: Quality::Medium,
+            sample_rate: 44100,
+            bit_depth: BitDepth::Int16,
+            format: AudioFormat::Wav,
+        }
     }
+}
 
-    let mut prev_elem = out[0];
-    return out
-        .iter()
-        .map(|elem| {
-            if elem.is_none() {
-                prev_elem
-            } else {
-                prev_elem = *elem;
-                *elem
-            }
-            .unwrap()
-        })
-        .collect();
+/// Sample formats of the output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitDepth {
+    Int16,
+    Int24,
+    Float32,
 }
 
-pub fn write(
-    data: &SongData,
-    world: &String,
-    verbose: bool,
-    data_path: &String,
-    out_path: &String,
+/// File formats of the created songs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    Ogg,
+}
+
+impl AudioFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Ogg => "ogg",
+        }
+    }
+}
+
+/// Metadata stored in the created files
+struct Tags {
+    title: String,
+    monsters: Vec<String>,
     tempo: f32,
     repeats: u8,
-) {
-    let mut out: Vec<i16> = vec![0; (data.duration * 44100.0) as usize + 5];
+}
 
-    for track in data.tracks.iter() {
-        if verbose {
-            println!("\x1b[90mProcessing track {}...\x1b[0m", track.name);
+impl Tags {
+    fn new(data: &SongData, monsters: Vec<String>, options: &WriteOptions) -> Self {
+        Tags {
+            title: data.island_name.to_owned(),
+            monsters,
+            tempo: options.tempo,
+            repeats: options.repeats,
         }
+    }
 
-        for part in track.parts.iter() {
-            if part.sound.is_none() {
-                continue;
-            }
-
-            let mut segment: Vec<i16>;
+    fn vorbis_comments(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("TITLE", self.title.clone()),
+            ("MONSTERS", self.monsters.join(", ")),
+            ("TEMPO", self.tempo.to_string()),
+            ("REPEATS", self.repeats.to_string()),
+        ]
+    }
 
-            let raw_segment_filename = if track.name == "Q_Monster" {
+    /// Entries of the `INFO` list of wav files, which has no fields for the other tags
+    fn riff_info(&self) -> Vec<(&'static [u8; 4], String)> {
+        vec![
+            (b"INAM", self.title.clone()),
+            (
+                b"ICMT",
                 format!(
-                    "{}/01-Q_Monster_{}",
-                    data_path,
-                    part.sound.as_ref().unwrap()
-                )
+                    "Monsters: {}; Tempo: {}; Repeats: {}",
+                    self.monsters.join(", "),
+                    self.tempo,
+                    self.repeats
+                ),
+            ),
+        ]
+    }
+}
+
+/// Receives lines of output while rendering. Prints them to stdout by default
+#[derive(Clone, Default)]
+pub struct Log(Option<Arc<Printer>>);
+
+type Printer = dyn Fn(&str) + Send + Sync;
+
+impl Log {
+    /// Passes every line to `print` instead, e.g. to show it above a progress display
+    pub fn new(print: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        Log(Some(Arc::new(print)))
+    }
+
+    pub fn line(&self, line: &str) {
+        match &self.0 {
+            Some(print) => print(line),
+            None => cprintln!("{}", line),
+        }
+    }
+}
+
+impl fmt::Debug for Log {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() {
+            "Log(custom)"
+        } else {
+            "Log(stdout)"
+        })
+    }
+}
+
+/// Statistics about a rendered song
+#[derive(Debug, Clone, Copy)]
+pub struct WriteReport {
+    /// Number of samples exceeding the 16-bit range before processing the mix, i.e. samples that
+    /// would clip without normalization or limiting
+    pub clipped_samples: usize,
+}
+
+/// Spreads the monsters of a song evenly from left to right, in the order they appear in
+/// `monster_names.json`
+pub fn default_pans(data: &SongData, monster_names: &Map<String, Value>) -> HashMap<String, f32> {
+    let mut names: Vec<String> = data.tracks.iter().map(|it| it.monster_name()).collect();
+    names.sort_unstable_by_key(|name| {
+        monster_names
+            .keys()
+            .position(|e| e == name)
+            .unwrap_or(usize::MAX)
+    });
+    names.dedup();
+    let count = names.len();
+    names
+        .into_iter()
+        .enumerate()
+        .map(|(index, name)| {
+            let pan = if count < 2 {
+                0.0
             } else {
-                format!(
-                    "{}/{}-{}_{}",
-                    data_path,
-                    data.island,
-                    track.name,
-                    part.sound.as_ref().unwrap()
-                )
+                index as f32 / (count - 1) as f32 * 1.6 - 0.8
+            };
+            (name, pan)
+        })
+        .collect()
+}
+
+/// Reads the segment a track plays for a sound as one sample vector per channel, normalized to
+/// -1..1 and resampled to `options.sample_rate`. Segments are only decoded once per `cache`
+fn read_segment(
+    assets: &dyn AssetSource,
+    cache: &SampleCache,
+    island: &str,
+    track: &Track,
+    sound: &str,
+    options: &WriteOptions,
+) -> Result<Segment> {
+    // Missing segments are reported by `open_sample`
+    let path = match assets.resolve_sample(island, &track.name, sound) {
+        Some((name, _)) => assets.display_path(&name),
+        None => assets::sample_name(island, &track.name, sound),
+    };
+    cache.get_or_decode(
+        &path,
+        options.sample_rate,
+        options.resample_quality,
+        || assets.open_sample(island, &track.name, sound),
+        |sample| {
+            let (channels, sample_rate) = decode_sample(sample)?;
+            Ok(channels
+                .into_iter()
+                .map(|channel| {
+                    let channel: Vec<f32> =
+                        channel.into_iter().map(|it| it as f32 / 32768.0).collect();
+                    if sample_rate == options.sample_rate {
+                        channel
+                    } else {
+                        let new_len = (options.sample_rate as f64
+                            * (channel.len() as f64 / sample_rate as f64))
+                            as usize;
+                        resample::resample(&channel, new_len, options.resample_quality)
+                    }
+                })
+                .collect())
+        },
+    )
+}
+
+/// Decodes a segment into one sample vector per channel. Also returns the sample rate
+fn decode_sample(sample: Sample) -> Result<(Vec<Vec<i16>>, u32)> {
+    match sample.format {
+        SampleFormat::Wav => {
+            let mut reader = match hound::WavReader::new(Cursor::new(sample.bytes)) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    return Err(MsmError::SegmentOpen {
+                        path: sample.path,
+                        source: e.into(),
+                    })
+                }
             };
-            if std::path::PathBuf::from(format!("{}.wav", raw_segment_filename)).exists() {
-                let segment_filename = format!("{}.wav", raw_segment_filename);
-                let mut segment_reader =
-                    hound::WavReader::open(&segment_filename).unwrap_or_else(|e| {
-                        eprintln!(
-                            "\x1b[31mError while opening \x1b[1m{}\x1b[22m: {}\x1b[0m",
-                            segment_filename, e
-                        );
-                        std::process::exit(10);
-                    });
-                segment = segment_reader
-                    .samples::<i16>()
-                    .map(|it| it.unwrap())
-                    .collect();
-                if segment_reader.spec().sample_rate != 44100 {
-                    segment = resize_vec(
-                        segment,
-                        (44100.0
-                            * (segment_reader.duration() as f64
-                                / segment_reader.spec().sample_rate as f64))
-                            as usize,
-                    );
+            let samples: Vec<i16> = match reader.samples::<i16>().collect() {
+                Ok(samples) => samples,
+                Err(e) => {
+                    return Err(MsmError::SegmentOpen {
+                        path: sample.path,
+                        source: e.into(),
+                    })
                 }
-            } else {
-                let segment_filename = format!("{}.ogg", raw_segment_filename);
-                let segment_file = std::fs::File::open(&segment_filename).unwrap_or_else(|e| {
-                    eprintln!(
-                        "\x1b[31mError while opening \x1b[1m{}\x1b[22m: {}\x1b[0m",
-                        segment_filename, e
-                    );
-                    std::process::exit(10);
-                });
-                let mut segment_reader = OggStreamReader::new(segment_file).unwrap_or_else(|e| {
-                    eprintln!(
-                        "\x1b[31mError while reading \x1b[1m{}\x1b[22m: {}\x1b[0m",
-                        segment_filename, e
-                    );
-                    std::process::exit(12);
-                });
-                segment = vec![];
-                while let Some(mut packet) = segment_reader.read_dec_packet().unwrap_or_else(|e| {
-                    eprintln!(
-                        "\x1b[31mError while reading \x1b[1m{}\x1b[22m: {}\x1b[0m",
-                        segment_filename, e
-                    );
-                    std::process::exit(12);
-                }) {
-                    segment.append(&mut packet[0]);
+            };
+            let channel_count = reader.spec().channels as usize;
+            let channels = (0..channel_count)
+                .map(|channel| {
+                    samples
+                        .iter()
+                        .skip(channel)
+                        .step_by(channel_count)
+                        .copied()
+                        .collect()
+                })
+                .collect();
+            Ok((channels, reader.spec().sample_rate))
+        }
+        SampleFormat::Ogg => {
+            let mut reader = match OggStreamReader::new(Cursor::new(sample.bytes)) {
+                Ok(reader) => reader,
+                Err(source) => {
+                    return Err(MsmError::SegmentRead {
+                        path: sample.path,
+                        source,
+                    })
                 }
-                if segment_reader.ident_hdr.audio_sample_rate != 44100 {
-                    let segment_len = segment.len();
-                    segment = resize_vec(
-                        segment,
-                        (44100.0
-                            * (segment_len as f64
-                                / segment_reader.ident_hdr.audio_sample_rate as f64))
-                            as usize,
-                    );
+            };
+            let mut channels: Vec<Vec<i16>> =
+                vec![vec![]; reader.ident_hdr.audio_channels as usize];
+            loop {
+                match reader.read_dec_packet() {
+                    Ok(Some(packet)) => {
+                        for (channel, mut samples) in channels.iter_mut().zip(packet) {
+                            channel.append(&mut samples);
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(source) => {
+                        return Err(MsmError::SegmentRead {
+                            path: sample.path,
+                            source,
+                        })
+                    }
                 }
             }
+            Ok((channels, reader.ident_hdr.audio_sample_rate))
+        }
+    }
+}
 
-            for (index, sample) in segment.iter().enumerate() {
-                let out_index = index + (44100f64 * part.start) as usize;
-                if index as f64 > 44100.0 * part.duration + 1.0 {
-                    /* println!("{}", track.name); */
-                    break;
-                }
-                if out_index >= out.len() {
-                    println!("\x1b[1;33mWarning: {} extended past song duration.\x1b[22m Cutting off...\x1b[0m", track.name);
-                    break;
+/// Parts whose note ends this close to the end of the song in seconds ring out completely
+const RING_OUT_MARGIN: f64 = 0.001;
+
+/// Creates an empty mix bus for one repetition of the song. Sounds ringing past its end extend it
+fn empty_bus(data: &SongData, options: &WriteOptions) -> Vec<Vec<f32>> {
+    let out_channels = if options.stereo { 2 } else { 1 };
+    vec![vec![0.0; loop_length(data, options)]; out_channels]
+}
+
+/// Length of one repetition of the song in samples
+fn loop_length(data: &SongData, options: &WriteOptions) -> usize {
+    (data.duration * options.sample_rate as f64).round() as usize
+}
+
+/// Number of samples of a segment played by a part. Parts are cut at the end of their note,
+/// except for those still playing at the end of the song. These ring out completely, so they can
+/// overlap the next repetition like in-game
+fn part_length(
+    data: &SongData,
+    part: &TrackPart,
+    segment_length: usize,
+    sample_rate: f64,
+) -> usize {
+    let available = segment_length.saturating_sub((sample_rate * part.offset) as usize);
+    if part.start + part.duration >= data.duration - RING_OUT_MARGIN {
+        available
+    } else {
+        available.min((sample_rate * part.duration + 1.0) as usize + 1)
+    }
+}
+
+/// Mixes all parts of a track into the mix bus, extending it for sounds ringing past its end
+fn render_track(
+    data: &SongData,
+    track: &Track,
+    assets: &dyn AssetSource,
+    cache: &SampleCache,
+    options: &WriteOptions,
+    out: &mut [Vec<f32>],
+) -> Result<()> {
+    if options.verbose {
+        options.log.line(&format!(
+            "\x1b[90mProcessing track {}...\x1b[0m",
+            track.name
+        ));
+    }
+
+    // Gains for the left and right channel
+    let pan = options
+        .pans
+        .get(&track.monster_name())
+        .copied()
+        .unwrap_or(0.0);
+    let gains = [(1.0 - pan).min(1.0), (1.0 + pan).min(1.0)];
+
+    for part in track.parts.iter() {
+        if part.sound.is_none() {
+            continue;
+        }
+
+        let segment = read_segment(
+            assets,
+            cache,
+            &data.island,
+            track,
+            part.sound.as_ref().unwrap(),
+            options,
+        )?;
+        let sample_rate = options.sample_rate as f64;
+        let start = (sample_rate * part.start) as usize;
+        let offset = (sample_rate * part.offset) as usize;
+        let length = part_length(data, part, segment[0].len(), sample_rate);
+        if start + length > out[0].len() {
+            for channel in out.iter_mut() {
+                channel.resize(start + length, 0.0);
+            }
+        }
+
+        if out.len() == 1 && segment.len() > 1 {
+            // Sources with more channels are downmixed for mono output
+            let gain = 1.0 / segment.len() as f32;
+            for segment_channel in segment.iter() {
+                for (index, sample) in segment_channel.iter().skip(offset).take(length).enumerate()
+                {
+                    out[0][start + index] += sample * gain;
                 }
-                out[out_index] = out[out_index].saturating_add(*sample);
+            }
+            continue;
+        }
+        for (out_channel, out) in out.iter_mut().enumerate() {
+            // Mono sources are used for both output channels
+            let segment_channel = &segment[out_channel.min(segment.len() - 1)];
+            for (index, sample) in segment_channel.iter().skip(offset).take(length).enumerate() {
+                out[start + index] += if options.stereo {
+                    sample * gains[out_channel]
+                } else {
+                    *sample
+                };
             }
         }
     }
+    Ok(())
+}
 
-    std::fs::create_dir_all(out_path).unwrap_or_else(|e| {
-        eprintln!(
-            "\x1b[31mError while creating directory \x1b[1m{}\x1b[22m: {}",
-            out_path, e
-        );
-        std::process::exit(14);
-    });
+/// Length of the mix bus after rendering every track, i.e. one repetition plus the longest sound
+/// ringing past its end
+fn bus_length(
+    data: &SongData,
+    assets: &dyn AssetSource,
+    cache: &SampleCache,
+    options: &WriteOptions,
+) -> Result<usize> {
+    let sample_rate = options.sample_rate as f64;
+    let mut length = loop_length(data, options);
+    for track in data.tracks.iter() {
+        for part in track.parts.iter() {
+            let sound = match &part.sound {
+                Some(sound) if part.start + part.duration >= data.duration - RING_OUT_MARGIN => {
+                    sound
+                }
+                _ => continue,
+            };
+            let segment = read_segment(assets, cache, &data.island, track, sound, options)?;
+            length = length.max(
+                (sample_rate * part.start) as usize
+                    + part_length(data, part, segment[0].len(), sample_rate),
+            );
+        }
+    }
+    Ok(length)
+}
 
+/// The song repeated `repeats` times. The sounds ringing past the end of a repetition overlap the
+/// start of the next one, like when the song loops in-game.
+///
+/// Only the samples that differ from each other are kept: the first repetitions up to the one
+/// that all earlier sounds ring into, followed by the sounds ringing past the end of the last
+/// repetition. Every repetition in between equals the last kept one
+pub(crate) struct Repeated {
+    samples: Vec<Vec<f32>>,
+    /// Length of one repetition in samples
+    period: usize,
+    /// Number of repetitions kept in `samples`
+    kept: usize,
+    repeats: usize,
+    /// Total length in samples
+    pub len: usize,
+    /// Start and length of the fade-out in samples
+    fade: (usize, usize),
+}
+
+impl Repeated {
+    /// Overlaps the repetitions of `bus`, which holds one repetition of `period` samples followed
+    /// by the sounds ringing past its end. `options.repeats` must not be 0
+    fn new(bus: &[Vec<f32>], period: usize, options: &WriteOptions) -> Self {
+        let repeats = options.repeats as usize;
+        let period = period.max(1);
+        let bus_length = bus[0].len();
+        let len = if options.tail {
+            ((repeats - 1) * period + bus_length).max(repeats * period)
+        } else {
+            repeats * period
+        };
+        // A repetition rings into this many following ones
+        let overlapped = bus_length.saturating_sub(1) / period;
+        let kept = repeats.min(overlapped + 1);
+
+        // Sum of all repetitions sounding at `index`
+        let overlap = |bus: &[f32], index: usize| -> f32 {
+            let first = (index + 1).saturating_sub(bus.len()).div_ceil(period);
+            let last = (index / period).min(repeats - 1);
+            (first..=last)
+                .map(|repetition| bus.get(index - repetition * period).copied().unwrap_or(0.0))
+                .sum()
+        };
+        let samples = bus
+            .iter()
+            .map(|bus| {
+                (0..kept * period)
+                    .chain(repeats * period..len)
+                    .map(|index| overlap(bus, index))
+                    .collect()
+            })
+            .collect();
+
+        let fade_length = ((options.fade * options.sample_rate as f32) as usize).min(len);
+        Repeated {
+            samples,
+            period,
+            kept,
+            repeats,
+            len,
+            fade: (len - fade_length, fade_length),
+        }
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Length of one repetition in samples
+    pub fn period(&self) -> usize {
+        self.period
+    }
+
+    /// Processes the mix like [`mix::process`] and returns the number of samples that clipped
+    /// before, counted over all repetitions
+    fn process(&mut self, mix: Mix, sample_rate: u32) -> usize {
+        let repeated = (self.kept - 1) * self.period..self.kept * self.period;
+        let clipped = self
+            .samples
+            .iter()
+            .flat_map(|channel| channel.iter().enumerate())
+            .filter(|(_, sample)| mix::is_clipped(**sample))
+            .map(|(index, _)| {
+                if repeated.contains(&index) {
+                    self.repeats - self.kept + 1
+                } else {
+                    1
+                }
+            })
+            .sum();
+        let count = self.repeats - self.kept + 1;
+        mix::process_repeated(&mut self.samples, mix, sample_rate, repeated, count);
+        clipped
+    }
+
+    /// A song consisting of `channels` played once, for testing the encoders
+    #[cfg(test)]
+    pub(crate) fn from_channels(channels: &[Vec<f32>], sample_rate: u32) -> Self {
+        Repeated::new(
+            channels,
+            channels[0].len(),
+            &WriteOptions {
+                sample_rate,
+                ..WriteOptions::default()
+            },
+        )
+    }
+
+    pub fn sample(&self, channel: usize, index: usize) -> f32 {
+        let kept_length = self.kept * self.period;
+        let position = if index < kept_length {
+            index
+        } else if index < self.repeats * self.period {
+            kept_length - self.period + (index - kept_length) % self.period
+        } else {
+            kept_length + index - self.repeats * self.period
+        };
+        let sample = self.samples[channel][position];
+
+        let (fade_start, fade_length) = self.fade;
+        if index < fade_start {
+            sample
+        } else {
+            let progress = (index - fade_start) as f32 / fade_length as f32;
+            sample * (0.5 + 0.5 * (std::f32::consts::PI * progress).cos())
+        }
+    }
+}
+
+/// Applies the tempo and pitch and writes the mix bus `repeats` times to
+/// `{out_path}/{name}.{extension}`. `period` is the length of one repetition in the bus. The
+/// repetitions are processed with `mix` after overlapping them. Returns the number of samples that
+/// clipped before processing
+fn write_audio(
+    out: Vec<Vec<f32>>,
+    period: usize,
+    out_path: &str,
+    name: &str,
+    options: &WriteOptions,
+    mix: Option<Mix>,
+    tags: &Tags,
+) -> Result<usize> {
+    // `Repeated` needs at least one repetition
+    if options.repeats == 0 {
+        return Err(MsmError::InvalidRepeat(options.repeats));
+    }
+    let mut out = out;
+    let bus_length = out[0].len();
+    if let Err(source) = std::fs::create_dir_all(out_path) {
+        return Err(MsmError::CreateDir {
+            path: out_path.to_owned(),
+            source,
+        });
+    }
+
+    if options.pitch != 0.0 {
+        // Resampling changes pitch and speed, stretching back to the original length restores
+        // the speed
+        let ratio = 2f32.powf(options.pitch / 12.0);
+        let resampled: Vec<Vec<f32>> = out
+            .iter()
+            .map(|channel| {
+                let new_len = (channel.len() as f32 / ratio).round() as usize;
+                resample::resample(channel, new_len, options.resample_quality)
+            })
+            .collect();
+        out = stretch::time_stretch(&resampled, 1.0 / ratio, options.sample_rate);
+    }
+    if options.tempo != 1.0 {
+        out = if options.time_stretch {
+            stretch::time_stretch(&out, options.tempo, options.sample_rate)
+        } else {
+            out.iter()
+                .map(|channel| {
+                    let new_len = (channel.len() as f32 / options.tempo).round() as usize;
+                    resample::resample(channel, new_len, options.resample_quality)
+                })
+                .collect()
+        };
+    }
+
+    // The tempo and pitch change the length of the bus, and with it the length of a repetition
+    let period = (period as f64 * out[0].len() as f64 / bus_length as f64).round() as usize;
+    let mut song = Repeated::new(&out, period, options);
+    drop(out);
+    let clipped = match mix {
+        Some(mix) => song.process(mix, options.sample_rate),
+        None => 0,
+    };
+
+    let path = format!("{}/{}.{}", out_path, name, options.format.extension());
+    match options.format {
+        AudioFormat::Wav => write_wav(&song, &path, options, tags)?,
+        AudioFormat::Flac => {
+            // FLAC has no floating point samples
+            let bits = match options.bit_depth {
+                BitDepth::Int16 => 16,
+                _ => 24,
+            };
+            std::fs::File::create(&path)
+                .and_then(|file| {
+                    flac::write_flac(
+                        BufWriter::new(file),
+                        &song,
+                        options.sample_rate,
+                        bits,
+                        &tags.vorbis_comments(),
+                    )
+                })
+                .map_err(|source| MsmError::AudioWrite { path, source })?
+        }
+        AudioFormat::Ogg => std::fs::File::create(&path)
+            .and_then(|file| {
+                vorbis::write_vorbis(
+                    BufWriter::new(file),
+                    &song,
+                    options.sample_rate,
+                    &tags.vorbis_comments(),
+                )
+            })
+            .map_err(|source| MsmError::AudioWrite { path, source })?,
+    }
+    Ok(clipped)
+}
+
+/// Writes the song as a wav file with a `LIST` chunk holding the tags
+fn write_wav(song: &Repeated, path: &str, options: &WriteOptions, tags: &Tags) -> Result<()> {
     let spec = hound::WavSpec {
-        channels: 1,
-        sample_rate: 44100,
-        bits_per_sample: 16,
-        sample_format: hound::SampleFormat::Int,
+        channels: song.channel_count() as u16,
+        sample_rate: options.sample_rate,
+        bits_per_sample: match options.bit_depth {
+            BitDepth::Int16 => 16,
+            BitDepth::Int24 => 24,
+            BitDepth::Float32 => 32,
+        },
+        sample_format: match options.bit_depth {
+            BitDepth::Float32 => hound::SampleFormat::Float,
+            _ => hound::SampleFormat::Int,
+        },
     };
-    let mut writer = hound::WavWriter::create(
-        format!(
-            "{}/{}_{}.wav",
+    let mut writer =
+        hound::WavWriter::create(path, spec).map_err(|source| MsmError::OutputWrite { source })?;
+    for index in 0..song.len {
+        for channel in 0..song.channel_count() {
+            let sample = song.sample(channel, index);
+            match options.bit_depth {
+                BitDepth::Int16 => writer.write_sample(quantize(sample, 16) as i16),
+                BitDepth::Int24 => writer.write_sample(quantize(sample, 24)),
+                BitDepth::Float32 => writer.write_sample(sample.clamp(-1.0, 1.0)),
+            }
+            .map_err(|source| MsmError::OutputWrite { source })?;
+        }
+    }
+    writer
+        .finalize()
+        .map_err(|source| MsmError::OutputWrite { source })?;
+
+    // hound can't write extra chunks, so the LIST chunk is appended and the RIFF size updated
+    let mut info = b"INFO".to_vec();
+    for (id, value) in tags.riff_info() {
+        let mut value = value.into_bytes();
+        value.push(0);
+        info.extend(id);
+        info.extend((value.len() as u32).to_le_bytes());
+        if value.len() % 2 == 1 {
+            value.push(0);
+        }
+        info.extend(value);
+    }
+    let mut chunk = b"LIST".to_vec();
+    chunk.extend((info.len() as u32).to_le_bytes());
+    chunk.extend(info);
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .and_then(|mut file| {
+            // RIFF chunks start at even offsets, but hound doesn't pad odd-length data chunks
+            if file.seek(SeekFrom::End(0))? % 2 == 1 {
+                chunk.insert(0, 0);
+            }
+            let riff_size = (file.stream_position()? - 8 + chunk.len() as u64)
+                .try_into()
+                .map_err(|_| io::Error::other("wav files can't be larger than 4 GiB"))?;
+            file.write_all(&chunk)?;
+            file.seek(SeekFrom::Start(4))?;
+            file.write_all(&u32::to_le_bytes(riff_size))
+        })
+        .map_err(|source| MsmError::OutputWrite {
+            source: hound::Error::IoError(source),
+        })
+}
+
+/// Converts a sample from -1..1 to a signed integer with the given number of bits, clipping it
+pub(crate) fn quantize(sample: f32, bits: u32) -> i32 {
+    let scale = (1 << (bits - 1)) as f32;
+    (sample * scale).round().clamp(-scale, scale - 1.0) as i32
+}
+
+pub fn write(
+    data: &SongData,
+    world: &str,
+    assets: &dyn AssetSource,
+    cache: &SampleCache,
+    out_path: &str,
+    options: &WriteOptions,
+    monster_names: &Map<String, Value>,
+) -> Result<WriteReport> {
+    let mut out = empty_bus(data, options);
+    for track in data.tracks.iter() {
+        render_track(data, track, assets, cache, options, &mut out)?;
+    }
+
+    let mut tracks = data.tracks.clone();
+    crate::sort_tracks(&mut tracks, monster_names);
+    let mut monsters: Vec<String> = tracks
+        .iter()
+        .map(|track| display_name(track, monster_names))
+        .collect();
+    monsters.dedup();
+
+    let clipped_samples = write_audio(
+        out,
+        loop_length(data, options),
+        out_path,
+        &format!("{}_{}", world, data.island_name.replace(' ', "-")),
+        options,
+        Some(options.mix),
+        &Tags::new(data, monsters, options),
+    )?;
+    Ok(WriteReport { clipped_samples })
+}
+
+/// Writes every monster of the song to its own file named `{world}_{monster}.{extension}`, using the display
+/// names from `monster_names.json`. All stems have the same length as the full song, so they line
+/// up when imported together. The mix processing of `options` is not applied to stems, so they
+/// still add up to the unprocessed song
+pub fn write_stems(
+    data: &SongData,
+    world: &str,
+    assets: &dyn AssetSource,
+    cache: &SampleCache,
+    out_path: &str,
+    options: &WriteOptions,
+    monster_names: &Map<String, Value>,
+) -> Result<()> {
+    let file_name = |track: &Track| display_name(track, monster_names).replace(' ', "-");
+    // Stems are extended to the length of the full song including the sounds ringing past its end
+    let length = bus_length(data, assets, cache, options)?;
+
+    // Tracks of the same monster share a stem, e.g. two MIDI tracks renamed to the same monster
+    let mut monsters: Vec<(String, Vec<&Track>)> = vec![];
+    for track in data.tracks.iter() {
+        let monster = track.monster_name();
+        match monsters.iter_mut().find(|(it, _)| *it == monster) {
+            Some((_, tracks)) => tracks.push(track),
+            None => monsters.push((monster, vec![track])),
+        }
+    }
+
+    for (monster, tracks) in monsters.iter() {
+        let track = tracks[0];
+        let mut name = file_name(track);
+        // Some monsters share a display name, e.g. the different Castles
+        if monsters
+            .iter()
+            .any(|(other, tracks)| other != monster && file_name(tracks[0]) == name)
+        {
+            name = format!("{}-{}", name, monster);
+        }
+
+        let mut out = empty_bus(data, options);
+        for track in tracks.iter() {
+            render_track(data, track, assets, cache, options, &mut out)?;
+        }
+        for channel in out.iter_mut() {
+            channel.resize(length, 0.0);
+        }
+        write_audio(
+            out,
+            loop_length(data, options),
             out_path,
-            world,
-            ISLAND_NAMES[world.parse::<usize>().unwrap()].replace(' ', "-")
-        ),
-        spec,
-    )
-    .unwrap_or_else(|e| {
-        eprintln!("\x1b[31mError while creating output file: {}\x1b[0m", e);
-        std::process::exit(11);
-    });
+            &format!("{}_{}", world, name),
+            options,
+            None,
+            &Tags::new(data, vec![display_name(track, monster_names)], options),
+        )?;
+    }
+    Ok(())
+}
+
+/// The display name of a track's monster from `monster_names.json`
+fn display_name(track: &Track, monster_names: &Map<String, Value>) -> String {
+    monster_names
+        .get(&track.monster_name())
+        .and_then(|it| it["name"].as_str())
+        .unwrap_or(track.name.as_str())
+        .to_owned()
+}
+
+/// A chord with some noise, different for every channel, for testing the encoders
+#[cfg(test)]
+pub(crate) fn test_signal(channels: usize, length: usize) -> Vec<Vec<f32>> {
+    let mut noise: u32 = 1;
+    (0..channels)
+        .map(|channel| {
+            (0..length)
+                .map(|index| {
+                    noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    let time = index as f32 / 44100.0;
+                    0.4 * (time * 220.0 * (channel + 1) as f32 * std::f32::consts::TAU).sin()
+                        + 0.3 * (time * 331.0 * std::f32::consts::TAU).sin()
+                        + 0.1 * (noise as f32 / u32::MAX as f32 - 0.5)
+                })
+                .collect()
+        })
+        .collect()
+}
 
-    if tempo != 1.0 {
-        out = resize_vec(out.clone(), (out.len() as f32 / tempo).round() as usize);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Overlaps every repetition of `bus` without skipping any, as `Repeated` should
+    fn naive(bus: &[f32], period: usize, repeats: usize, len: usize) -> Vec<f32> {
+        let mut out = vec![0.0; len.max(repeats * period + bus.len())];
+        for repetition in 0..repeats {
+            for (index, sample) in bus.iter().enumerate() {
+                out[repetition * period + index] += sample;
+            }
+        }
+        out.truncate(len);
+        out
     }
-    for _ in 0..repeats {
-        for sample in out.iter() {
-            writer.write_sample(*sample).unwrap();
+
+    fn repeated(bus: &[f32], period: usize, repeats: u8, tail: bool, fade: f32) -> Repeated {
+        let options = WriteOptions {
+            repeats,
+            tail,
+            fade,
+            sample_rate: 8,
+            ..WriteOptions::default()
+        };
+        Repeated::new(&[bus.to_vec()], period, &options)
+    }
+
+    /// A bus of `period` 4 ringing 6 samples into the next two repetitions
+    fn ringing_bus() -> Vec<f32> {
+        (1..=10).map(|it| it as f32).collect()
+    }
+
+    #[test]
+    fn overlap() {
+        let bus = ringing_bus();
+        for (repeats, tail, kept, len) in [
+            (1, false, 1, 4),
+            (1, true, 1, 10),
+            (2, false, 2, 8),
+            (5, false, 3, 20),
+            (5, true, 3, 26),
+            (100, true, 3, 406),
+        ] {
+            let song = repeated(&bus, 4, repeats, tail, 0.0);
+            assert_eq!((song.kept, song.len), (kept, len), "{} repeats", repeats);
+            // Only the distinct repetitions and the tail are stored
+            assert_eq!(song.samples[0].len(), kept * 4 + len - repeats as usize * 4);
+            let expected = naive(&bus, 4, repeats as usize, len);
+            let actual: Vec<f32> = (0..len).map(|index| song.sample(0, index)).collect();
+            assert_eq!(actual, expected, "{} repeats", repeats);
         }
     }
-    writer.finalize().unwrap();
+
+    #[test]
+    fn short_bus() {
+        // Sounds ending before the repetition does don't overlap anything
+        let song = repeated(&[1.0, 2.0], 4, 3, true, 0.0);
+        assert_eq!((song.kept, song.len), (1, 12));
+        let actual: Vec<f32> = (0..12).map(|index| song.sample(0, index)).collect();
+        assert_eq!(actual, naive(&[1.0, 2.0], 4, 3, 12));
+    }
+
+    #[test]
+    fn fade() {
+        // 1 second at 8 Hz fades out the last 8 samples
+        let song = repeated(&[1.0; 4], 4, 4, false, 1.0);
+        assert_eq!(song.fade, (8, 8));
+        assert_eq!(song.sample(0, 7), 1.0);
+        assert_eq!(song.sample(0, 8), 1.0);
+        assert!((song.sample(0, 12) - 0.5).abs() < 1e-6);
+        assert!(song.sample(0, 15) < 0.05);
+        // Fades longer than the song start at its beginning
+        assert_eq!(repeated(&[1.0; 4], 4, 1, false, 10.0).fade, (0, 4));
+    }
+
+    #[test]
+    fn clipped_over_all_repetitions() {
+        let mut song = repeated(&[1.5, 0.0, 0.0, 0.0, 1.5], 4, 5, true, 0.0);
+        // The start of every repetition and the tail, the repetitions after the first overlapping
+        // to 3
+        assert_eq!(song.process(Mix::Clip, 44100), 6);
+    }
+
+    #[test]
+    fn reject_no_repeats() {
+        let options = WriteOptions {
+            repeats: 0,
+            ..WriteOptions::default()
+        };
+        let tags = Tags {
+            title: "Plant Island".to_owned(),
+            monsters: vec![],
+            tempo: 1.0,
+            repeats: 0,
+        };
+        let result = write_audio(
+            vec![vec![0.0; 4]],
+            4,
+            "unused",
+            "song",
+            &options,
+            None,
+            &tags,
+        );
+        assert!(matches!(result, Err(MsmError::InvalidRepeat(0))));
+    }
 }
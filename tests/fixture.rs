@@ -0,0 +1,370 @@
+//! Creates a song from a small synthetic island held in memory. The island has a tempo change
+//! after the first bar, so it also covers the tempo map and cutting a window out of the song.
+
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use msm::assets::MemorySource;
+use msm::cache::SampleCache;
+use msm::check::{self, Problem};
+use msm::parse::{self, Position};
+use msm::profile::Profile;
+use msm::write::{self, WriteOptions};
+use msm::MsmError;
+use std::io::Cursor;
+
+const TICKS_PER_BEAT: u16 = 480;
+const SAMPLE_RATE: u32 = 44100;
+
+/// Converts events with absolute ticks to a track lasting two bars
+fn track(
+    name: &'static str,
+    mut events: Vec<(u32, TrackEventKind<'static>)>,
+) -> Vec<TrackEvent<'static>> {
+    let end = 8 * TICKS_PER_BEAT as u32;
+    let name = TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes()));
+    events.insert(0, (0, name));
+    events.push((end, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+    let mut time = 0;
+    events
+        .into_iter()
+        .map(|(tick, kind)| {
+            let delta = tick - time;
+            time = tick;
+            TrackEvent {
+                delta: u28::new(delta),
+                kind,
+            }
+        })
+        .collect()
+}
+
+/// A note-on and note-off event for each `(start, end, key)`
+fn notes(notes: &[(u32, u32, u8)]) -> Vec<(u32, TrackEventKind<'static>)> {
+    let event = |key: u8, on: bool| TrackEventKind::Midi {
+        channel: u4::new(0),
+        message: if on {
+            MidiMessage::NoteOn {
+                key: u7::new(key),
+                vel: u7::new(100),
+            }
+        } else {
+            MidiMessage::NoteOff {
+                key: u7::new(key),
+                vel: u7::new(0),
+            }
+        },
+    };
+    notes
+        .iter()
+        .flat_map(|&(start, end, key)| [(start, event(key, true)), (end, event(key, false))])
+        .collect()
+}
+
+/// Two bars, the first at 120 and the second at 240 bpm, so the song lasts 3 seconds:
+///
+/// - Tweedle plays sound `01` from 0 to 0.5 seconds and `02` from 2.5 to 2.75 seconds
+/// - Potbelly plays sound `01` from 1 to 2 seconds
+/// - Noggin plays sound `01` from 2 to 2.25 seconds
+fn midi() -> Vec<u8> {
+    smf(vec![
+        track("A_Monster", notes(&[(0, 480, 72), (2880, 3360, 73)])),
+        track("B_Monster", notes(&[(960, 1920, 72)])),
+        track("C_Monster", notes(&[(1920, 2400, 72)])),
+    ])
+}
+
+/// A MIDI file with the tempo changes of [`midi`] and the given tracks
+fn smf(tracks: Vec<Vec<TrackEvent<'static>>>) -> Vec<u8> {
+    let tempo = |tick: u32, microseconds: u32| {
+        (
+            tick,
+            TrackEventKind::Meta(MetaMessage::Tempo(u24::new(microseconds))),
+        )
+    };
+    let conductor = track("Conductor", vec![tempo(0, 500_000), tempo(1920, 250_000)]);
+    let smf = Smf {
+        header: Header::new(Format::Parallel, Timing::Metrical(u15::new(TICKS_PER_BEAT))),
+        tracks: [conductor].into_iter().chain(tracks).collect(),
+    };
+    let mut bytes = vec![];
+    smf.write_std(&mut bytes).unwrap();
+    bytes
+}
+
+/// A one second WAV file. Each channel plays the first value of its pair in the first half and the
+/// second one in the second half
+fn wav(sample_rate: u32, channels: &[(f32, f32)]) -> Vec<u8> {
+    let spec = hound::WavSpec {
+        channels: channels.len() as u16,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut bytes = vec![];
+    let mut writer = hound::WavWriter::new(Cursor::new(&mut bytes), spec).unwrap();
+    for index in 0..sample_rate {
+        for &(first, second) in channels {
+            let value = if index < sample_rate / 2 {
+                first
+            } else {
+                second
+            };
+            writer.write_sample((value * 32767.0) as i16).unwrap();
+        }
+    }
+    writer.finalize().unwrap();
+    bytes
+}
+
+/// Every segment the island needs, Potbelly's changing its level halfway through
+fn complete_island() -> MemorySource {
+    let mut assets = MemorySource::new();
+    assets.insert("world01.mid", midi());
+    assets.insert("01-A_Monster_01.wav", wav(SAMPLE_RATE, &[(0.1, 0.1)]));
+    assets.insert("01-A_Monster_02.wav", wav(SAMPLE_RATE, &[(0.2, 0.2)]));
+    assets.insert("01-B_Monster_01.wav", wav(SAMPLE_RATE, &[(0.3, 0.4)]));
+    assets.insert("01-C_Monster_01.wav", wav(SAMPLE_RATE, &[(0.05, 0.05)]));
+    assets
+}
+
+#[test]
+fn parse_tempo_map() {
+    let assets = complete_island();
+    let song = parse::parse(&assets, "01", &Profile::embedded().island("01"), &[], &[]).unwrap();
+
+    assert_eq!(song.island_name, "Plant Island");
+    assert_eq!(song.duration, 3.0);
+    assert_eq!(song.tempo.len(), 2);
+    assert_eq!((song.tempo[1].start, song.tempo[1].start_beat), (2.0, 4.0));
+    assert_eq!(song.seconds_at(6.0), 2.5);
+
+    let parts: Vec<(&str, f64, f64, Option<&str>)> = song
+        .tracks
+        .iter()
+        .flat_map(|track| {
+            track.parts.iter().map(move |part| {
+                (
+                    track.name.as_str(),
+                    part.start,
+                    part.duration,
+                    part.sound.as_deref(),
+                )
+            })
+        })
+        .collect();
+    assert_eq!(
+        parts,
+        [
+            ("A_Monster", 0.0, 0.5, Some("01")),
+            ("A_Monster", 2.5, 0.25, Some("02")),
+            ("B_Monster", 1.0, 1.0, Some("01")),
+            ("C_Monster", 2.0, 0.25, Some("01")),
+        ]
+    );
+}
+
+#[test]
+fn write_window() {
+    let assets = complete_island();
+    let song = parse::parse(&assets, "01", &Profile::embedded().island("01"), &[], &[]).unwrap();
+    // Beat 3 at 120 bpm to beat 6 at 240 bpm
+    let start = song.position_seconds(Position::Bar { bar: 1, beat: 4.0 });
+    let end = song.position_seconds(Position::Bar { bar: 2, beat: 4.0 });
+    assert_eq!((start, end), (1.5, 2.75));
+    let song = song.window(start, end);
+
+    // Potbelly's note started before the window and continues from the middle of its sound
+    let potbelly = &song
+        .tracks
+        .iter()
+        .find(|it| it.name == "B_Monster")
+        .unwrap()
+        .parts[0];
+    assert_eq!(
+        (potbelly.start, potbelly.duration, potbelly.offset),
+        (0.0, 0.5, 0.5)
+    );
+    assert_eq!(song.tempo.len(), 2);
+    assert_eq!((song.tempo[1].start, song.tempo[1].start_beat), (0.5, 1.0));
+
+    let out_path = std::env::temp_dir().join(format!("msm-fixture-{}", std::process::id()));
+    let out_path = out_path.to_str().unwrap();
+    let report = write::write(
+        &song,
+        "01",
+        &assets,
+        &SampleCache::new(),
+        out_path,
+        &WriteOptions::default(),
+        &msm::monster_names(),
+    )
+    .unwrap();
+    assert_eq!(report.clipped_samples, 0);
+
+    let mut reader = hound::WavReader::open(format!("{}/01_Plant-Island.wav", out_path)).unwrap();
+    let samples: Vec<f32> = reader
+        .samples::<i16>()
+        .map(|it| it.unwrap() as f32 / 32767.0)
+        .collect();
+    std::fs::remove_dir_all(out_path).unwrap();
+
+    assert_eq!(samples.len(), (1.25 * SAMPLE_RATE as f64) as usize);
+    let at = |seconds: f64| samples[(seconds * SAMPLE_RATE as f64) as usize];
+    for (seconds, expected) in [(0.25, 0.4), (0.6, 0.05), (0.9, 0.0), (1.1, 0.2)] {
+        assert!(
+            (at(seconds) - expected).abs() < 1e-3,
+            "{}s: {}",
+            seconds,
+            at(seconds)
+        );
+    }
+}
+
+#[test]
+fn check_problems() {
+    let mut assets = MemorySource::new();
+    assets.insert("world01.mid", midi());
+    assets.insert("01-A_Monster_01.wav", wav(SAMPLE_RATE, &[(0.1, 0.1)]));
+    assets.insert("01-A_Monster_02.wav", wav(SAMPLE_RATE, &[(0.2, 0.2)]));
+    assets.insert("01-B_Monster_01.wav", wav(22050, &[(0.3, 0.4)]));
+    assets.insert("01-D_Monster_01.wav", wav(SAMPLE_RATE, &[(0.1, 0.1)]));
+    // World 11 isn't an island, so it isn't checked
+    assets.insert("world11.mid", vec![]);
+
+    let report = check::check(&assets, &Profile::embedded()).unwrap();
+    assert_eq!((report.sample_rate, report.channels), (SAMPLE_RATE, 1));
+    assert_eq!(report.unreferenced, ["01-D_Monster_01.wav"]);
+    assert_eq!(report.ignored, ["world11.mid"]);
+    assert_eq!(report.islands.len(), 1);
+    assert_eq!(report.failures(), 1);
+    let island = &report.islands[0];
+    assert_eq!((island.world.as_str(), island.samples), ("01", 4));
+    assert!(island.error.is_none());
+    let issues: Vec<(&str, &str, &Problem)> = island
+        .issues
+        .iter()
+        .map(|it| (it.file.as_str(), it.monster.as_str(), &it.problem))
+        .collect();
+    assert!(matches!(
+        issues[..],
+        [
+            (
+                "01-B_Monster_01.wav",
+                "B_Monster",
+                Problem::Format {
+                    sample_rate: 22050,
+                    channels: 1
+                }
+            ),
+            ("01-C_Monster_01", "C_Monster", Problem::Missing),
+        ]
+    ));
+
+    let song = parse::parse(&assets, "01", &Profile::embedded().island("01"), &[], &[]).unwrap();
+    let result = write::write(
+        &song,
+        "01",
+        &assets,
+        &SampleCache::new(),
+        "unused",
+        &WriteOptions::default(),
+        &msm::monster_names(),
+    );
+    assert!(matches!(result, Err(MsmError::SegmentOpen { .. })));
+}
+
+#[test]
+fn downmix_stereo_segments() {
+    let mut assets = complete_island();
+    assets.insert(
+        "01-C_Monster_01.wav",
+        wav(SAMPLE_RATE, &[(0.1, 0.1), (0.3, 0.3)]),
+    );
+    let song = parse::parse(&assets, "01", &Profile::embedded().island("01"), &[], &[]).unwrap();
+
+    let out_path = std::env::temp_dir().join(format!("msm-downmix-{}", std::process::id()));
+    let out_path = out_path.to_str().unwrap();
+    write::write(
+        &song,
+        "01",
+        &assets,
+        &SampleCache::new(),
+        out_path,
+        &WriteOptions::default(),
+        &msm::monster_names(),
+    )
+    .unwrap();
+    let mut reader = hound::WavReader::open(format!("{}/01_Plant-Island.wav", out_path)).unwrap();
+    let samples: Vec<i16> = reader.samples::<i16>().map(|it| it.unwrap()).collect();
+    std::fs::remove_dir_all(out_path).unwrap();
+
+    // Only Noggin plays at 2.1 seconds
+    let sample = samples[(2.1 * SAMPLE_RATE as f64) as usize] as f32 / 32767.0;
+    assert!((sample - 0.2).abs() < 1e-3, "{}", sample);
+}
+
+#[test]
+fn stems() {
+    let mut assets = complete_island();
+    // Tweedle is split into two tracks, and both Castles have the same display name
+    assets.insert(
+        "world01.mid",
+        smf(vec![
+            track("A_Monster", notes(&[(0, 480, 72)])),
+            track("B_Monster", notes(&[(960, 1920, 72)])),
+            track("A_Monster", notes(&[(2880, 3360, 73)])),
+            track("Bass", notes(&[(0, 480, 72)])),
+            track("Bass_Monster", notes(&[(480, 960, 72)])),
+        ]),
+    );
+    assets.insert("01-Bass_01.wav", wav(SAMPLE_RATE, &[(0.3, 0.3)]));
+    assets.insert("01-Bass_Monster_01.wav", wav(SAMPLE_RATE, &[(0.4, 0.4)]));
+    let song = parse::parse(&assets, "01", &Profile::embedded().island("01"), &[], &[]).unwrap();
+
+    let out_path = std::env::temp_dir().join(format!("msm-stems-{}", std::process::id()));
+    let out_path = out_path.to_str().unwrap();
+    write::write_stems(
+        &song,
+        "01",
+        &assets,
+        &SampleCache::new(),
+        out_path,
+        &WriteOptions::default(),
+        &msm::monster_names(),
+    )
+    .unwrap();
+    let mut files: Vec<String> = std::fs::read_dir(out_path)
+        .unwrap()
+        .map(|it| it.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    let read = |name: &str| -> Vec<f32> {
+        let mut reader = hound::WavReader::open(format!("{}/{}", out_path, name)).unwrap();
+        reader
+            .samples::<i16>()
+            .map(|it| it.unwrap() as f32 / 32767.0)
+            .collect()
+    };
+    let tweedle = read("01_Tweedle.wav");
+    let castles = [
+        read("01_Castle-Bass.wav"),
+        read("01_Castle-Bass_Monster.wav"),
+    ];
+    std::fs::remove_dir_all(out_path).unwrap();
+
+    assert_eq!(
+        files,
+        [
+            "01_Castle-Bass.wav",
+            "01_Castle-Bass_Monster.wav",
+            "01_Potbelly.wav",
+            "01_Tweedle.wav"
+        ]
+    );
+    // Both Tweedle tracks are in its stem
+    let at = |samples: &[f32], seconds: f64| samples[(seconds * SAMPLE_RATE as f64) as usize];
+    assert!((at(&tweedle, 0.25) - 0.1).abs() < 1e-3);
+    assert!((at(&tweedle, 2.6) - 0.2).abs() < 1e-3);
+    assert!((at(&castles[0], 0.1) - 0.3).abs() < 1e-3);
+    assert!((at(&castles[1], 0.75) - 0.4).abs() < 1e-3);
+}